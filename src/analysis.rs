@@ -0,0 +1,268 @@
+//! Text analysis.
+//!
+//! Before a document can be indexed, its text has to be broken into terms.
+//! An `Analyzer` does that in two steps: a `Tokenizer` splits the text into
+//! tokens, then a chain of `TokenFilter`s normalizes (or drops) each token.
+//!
+//! The same analyzer must be used to index documents and to analyze queries,
+//! otherwise a query term may never match the indexed terms. That's why the
+//! name of the analyzer is recorded in every index file.
+
+/// A single token produced by a `Tokenizer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    /// The text of the token, as it will be stored in the index.
+    pub text: String,
+
+    /// Byte offset of the first byte of the token in the original text.
+    pub start: usize,
+
+    /// Byte offset just past the last byte of the token in the original
+    /// text, so that `&text[start..end]` is the token as it appears there.
+    /// Filters may change `text`, but never the offsets.
+    pub end: usize,
+
+    /// Ordinal of the token among all tokens produced by the tokenizer,
+    /// counting from 0. `Analyzer::analyze` assigns it before filtering, so
+    /// dropped tokens leave a gap.
+    pub position: u32,
+}
+
+/// Breaks text into tokens.
+pub trait Tokenizer: Send + Sync {
+    fn tokenize(&self, text: &str) -> Vec<Token>;
+}
+
+/// Transforms a token after tokenization.
+///
+/// Returning `None` drops the token from the token stream.
+pub trait TokenFilter: Send + Sync {
+    fn filter(&self, token: Token) -> Option<Token>;
+}
+
+/// Splits text on every character that is not alphanumeric.
+#[derive(Debug, Default)]
+pub struct AlphanumericTokenizer;
+
+impl Tokenizer for AlphanumericTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        split_tokens(text, char::is_alphanumeric)
+    }
+}
+
+/// Splits text on whitespace only, so punctuation stays part of the token.
+#[derive(Debug, Default)]
+pub struct WhitespaceTokenizer;
+
+impl Tokenizer for WhitespaceTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        split_tokens(text, |ch| !ch.is_whitespace())
+    }
+}
+
+/// Lowercases every token.
+///
+/// 'İ' (capital I with dot) becomes a plain 'i'. Its full lowercase mapping
+/// is 'i' followed by a combining dot, which is not alphanumeric, so a query
+/// could never contain the resulting term.
+#[derive(Debug, Default)]
+pub struct LowercaseFilter;
+
+impl TokenFilter for LowercaseFilter {
+    fn filter(&self, mut token: Token) -> Option<Token> {
+        token.text = token.text.replace('İ', "I").to_lowercase();
+        Some(token)
+    }
+}
+
+/// A tokenizer followed by a chain of token filters.
+///
+/// Every analyzer has a name. The name is written into index files, so an
+/// analyzer with a given name must always produce the same terms.
+pub struct Analyzer {
+    name: String,
+    tokenizer: Box<dyn Tokenizer>,
+    filters: Vec<Box<dyn TokenFilter>>,
+}
+
+impl Analyzer {
+    /// Name of the analyzer used when none is specified.
+    pub const STANDARD: &'static str = "standard";
+
+    /// Name of the analyzer that splits on whitespace.
+    pub const WHITESPACE: &'static str = "whitespace";
+
+    /// Create an analyzer with the given name, and no token filters.
+    pub fn new<T: Tokenizer + 'static>(name: &str, tokenizer: T) -> Analyzer {
+        Analyzer {
+            name: name.to_string(),
+            tokenizer: Box::new(tokenizer),
+            filters: vec![],
+        }
+    }
+
+    /// Append a token filter to the end of the filter chain.
+    pub fn with_filter<F: TokenFilter + 'static>(mut self, filter: F) -> Analyzer {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// The analyzer that splits text on alphanumeric boundaries and
+    /// lowercases every token.
+    pub fn standard() -> Analyzer {
+        Analyzer::new(Self::STANDARD, AlphanumericTokenizer).with_filter(LowercaseFilter)
+    }
+
+    /// The analyzer that splits text on whitespace and lowercases every token.
+    pub fn whitespace() -> Analyzer {
+        Analyzer::new(Self::WHITESPACE, WhitespaceTokenizer).with_filter(LowercaseFilter)
+    }
+
+    /// Look up one of the built-in analyzers by name.
+    pub fn by_name(name: &str) -> Option<Analyzer> {
+        match name {
+            Self::STANDARD => Some(Self::standard()),
+            Self::WHITESPACE => Some(Self::whitespace()),
+            _ => None,
+        }
+    }
+
+    /// The name recorded in index files built with this analyzer.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Break `text` into tokens and run them through the filter chain.
+    pub fn analyze(&self, text: &str) -> Vec<Token> {
+        self.tokenizer
+            .tokenize(text)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(position, mut token)| {
+                token.position = position as u32;
+                self.filters
+                    .iter()
+                    .try_fold(token, |token, filter| filter.filter(token))
+            })
+            .collect()
+    }
+}
+
+impl Default for Analyzer {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+/// Split `text` into maximal runs of characters for which `is_token_char`
+/// returns true.
+fn split_tokens(text: &str, is_token_char: impl Fn(char) -> bool) -> Vec<Token> {
+    let mut res = Vec::new();
+    let mut token_start = None;
+    for (idx, ch) in text.char_indices() {
+        match (is_token_char(ch), token_start) {
+            // start of a word
+            (true, None) => token_start = Some(idx),
+            // end of a word
+            (false, Some(start)) => {
+                res.push(Token {
+                    text: text[start..idx].to_string(),
+                    start,
+                    end: idx,
+                    position: res.len() as u32,
+                });
+                token_start = None
+            }
+            _ => {}
+        }
+    }
+
+    // the last one.
+    if let Some(start) = token_start {
+        res.push(Token {
+            text: text[start..].to_string(),
+            start,
+            end: text.len(),
+            position: res.len() as u32,
+        })
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text, offsets and position of every token.
+    fn tokens(analyzer: &Analyzer, text: &str) -> Vec<(String, usize, usize, u32)> {
+        analyzer
+            .analyze(text)
+            .into_iter()
+            .map(|token| (token.text, token.start, token.end, token.position))
+            .collect()
+    }
+
+    /// Drops tokens of at most 2 bytes, like a crude stop word filter.
+    struct DropShort;
+
+    impl TokenFilter for DropShort {
+        fn filter(&self, token: Token) -> Option<Token> {
+            (token.text.len() > 2).then_some(token)
+        }
+    }
+
+    /// Appends `suffix` to every token.
+    struct Suffix(&'static str);
+
+    impl TokenFilter for Suffix {
+        fn filter(&self, mut token: Token) -> Option<Token> {
+            token.text.push_str(self.0);
+            Some(token)
+        }
+    }
+
+    #[test]
+    fn whitespace_analyzer_keeps_punctuation() {
+        let analyzer = Analyzer::by_name(Analyzer::WHITESPACE).unwrap();
+        assert_eq!(analyzer.name(), "whitespace");
+        assert_eq!(
+            tokens(&analyzer, "  Don't\tPANIC,\nfriend! "),
+            [
+                ("don't".to_string(), 2, 7, 0),
+                ("panic,".to_string(), 8, 14, 1),
+                ("friend!".to_string(), 15, 22, 2),
+            ]
+        );
+        assert_eq!(tokens(&analyzer, " \n\t"), []);
+    }
+
+    #[test]
+    fn filters_run_in_the_order_they_were_added() {
+        let text = "go rust";
+        let drop_first = Analyzer::new("a", WhitespaceTokenizer)
+            .with_filter(DropShort)
+            .with_filter(Suffix("!"));
+        assert_eq!(tokens(&drop_first, text), [("rust!".to_string(), 3, 7, 1)]);
+        // "go" is long enough to keep once the suffix is appended.
+        let suffix_first = Analyzer::new("b", WhitespaceTokenizer)
+            .with_filter(Suffix("!"))
+            .with_filter(DropShort);
+        assert_eq!(
+            tokens(&suffix_first, text),
+            [("go!".to_string(), 0, 2, 0), ("rust!".to_string(), 3, 7, 1)]
+        );
+    }
+
+    #[test]
+    fn dropped_tokens_leave_gaps_in_positions() {
+        let analyzer = Analyzer::standard().with_filter(DropShort);
+        assert_eq!(
+            tokens(&analyzer, "Rust is a safe language"),
+            [
+                ("rust".to_string(), 0, 4, 0),
+                ("safe".to_string(), 10, 14, 3),
+                ("language".to_string(), 15, 23, 4),
+            ]
+        );
+    }
+}
@@ -0,0 +1,22 @@
+//! Code shared by the binaries.
+//!
+//! The library never prints diagnostics; it emits `tracing` events, and
+//! leaves it to whoever embeds it to collect them. Each binary calls
+//! `init_logging` once, at startup, to send them to stderr.
+
+use std::io;
+
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+
+/// Send log records to stderr, at the level set by `RUST_LOG` (warnings and
+/// errors by default).
+pub fn init_logging() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::WARN.into())
+                .from_env_lossy(),
+        )
+        .with_writer(io::stderr)
+        .init();
+}
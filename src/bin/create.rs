@@ -1,47 +1,85 @@
+mod common;
+
 use std::{
-    fs::File,
-    io::{self, Read},
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, IsTerminal, Read},
+    ops::AddAssign,
     path::{Path, PathBuf},
-    sync::mpsc::{channel, Receiver},
-    thread::{spawn, JoinHandle},
+    process,
+    sync::{
+        mpsc::{sync_channel, Receiver, SendError, SyncSender, TrySendError},
+        Arc, Condvar, Mutex,
+    },
+    thread::{available_parallelism, spawn, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 
 use clap::Parser;
 use inverted_index_concurrency::{
-    index::InMemoryIndex, merge::FileMerge, tmp::TmpDir, write::write_index_to_tmp_file,
+    analysis::Analyzer,
+    error::{self, IndexError},
+    files::expand_filename_arguments,
+    index::{DocIdAllocator, InMemoryIndex},
+    merge::{FileMerge, DEFAULT_NSTREAMS},
+    progress::{Progress, ProgressReporter, ProgressStyle},
+    tmp::TmpDir,
+    write::write_index_to_tmp_file,
 };
 
+use common::init_logging;
+
 /// Create an inverted index for the given list of `documents`,
 /// storing it in the specified `output_dir`.
-fn run_single_threaded(documents: Vec<PathBuf>, output_dir: PathBuf) -> io::Result<()> {
+///
+/// The in-memory index is written to a temporary file whenever it uses
+/// `opts.memory_budget` bytes. Everything that is done is recorded in
+/// `progress`.
+fn run_single_threaded(
+    documents: Vec<PathBuf>,
+    output_dir: PathBuf,
+    analyzer: Analyzer,
+    progress: &Progress,
+    opts: &Opts,
+) -> error::Result<()> {
     // If all the documents fit comfortably in memory, we'll create the whole
     // index in memory.
-    let mut accumulated_index = InMemoryIndex::new();
+    let mut accumulated_index = InMemoryIndex::with_analyzer(analyzer.name());
 
     // If not, then as memory fills up, we'll write largeish temporary index
     // files to disk, saving the temporary filenames in `merge` so that later we
-    // can merge them all into a single huge file.
-    let mut merge = FileMerge::new(&output_dir);
+    // can merge them all into a single huge file. Merges run on this thread.
+    let mut merge = FileMerge::new(&output_dir, opts.merge_streams, 0, progress.clone());
 
     // A tool for generating temporary filenames.
     let mut tmp_dir = TmpDir::new(&output_dir);
 
+    let mut doc_ids = DocIdAllocator::new();
+
     // For each document in the set...
-    for (doc_id, filename) in documents.into_iter().enumerate() {
+    for filename in documents {
         // ...load it into memory...
-        let mut f = File::open(filename.clone())?;
-        let mut text = String::new();
-        f.read_to_string(&mut text)?;
+        let (text, modified) = read_document(&filename)?;
+        progress.document_read();
 
         // ...and add its contents to the in-memory `accumulated_index`.
-        // doc_id start from 1
-        let index = InMemoryIndex::from_single_document((doc_id + 1) as u32, filename, text);
+        let len = text.len() as u64;
+        let index = InMemoryIndex::from_single_document(
+            &analyzer,
+            doc_ids.allocate(),
+            filename,
+            text,
+            modified,
+            opts.store_text,
+        );
+        progress.text_indexed(len);
         accumulated_index.merge(index);
-        if accumulated_index.is_large() {
+        if accumulated_index.exceeds(opts.memory_budget) {
             // To avoid running out of memory, dump `accumulated_index` to disk.
             let file = write_index_to_tmp_file(accumulated_index, &mut tmp_dir)?;
+            progress.tmp_file_written();
             merge.add_file(file)?;
-            accumulated_index = InMemoryIndex::new();
+            accumulated_index = InMemoryIndex::with_analyzer(analyzer.name());
         }
     }
 
@@ -49,97 +87,345 @@ fn run_single_threaded(documents: Vec<PathBuf>, output_dir: PathBuf) -> io::Resu
     // temporary index files if there are more than one.
     if !accumulated_index.is_empty() {
         let file = write_index_to_tmp_file(accumulated_index, &mut tmp_dir)?;
+        progress.tmp_file_written();
         merge.add_file(file)?;
     }
     merge.finish()
 }
 
+/// Read the text of the file at `path`, and its modification time. Errors
+/// name the file.
+fn read_document(path: &Path) -> error::Result<(String, SystemTime)> {
+    let read = || -> io::Result<(String, SystemTime)> {
+        let mut f = File::open(path)?;
+        let modified = f.metadata()?.modified()?;
+        let mut text = String::new();
+        f.read_to_string(&mut text)?;
+        Ok((text, modified))
+    };
+    read().map_err(IndexError::file_io(path))
+}
+
+/// A document loaded into memory: its id, its path, its content and the
+/// modification time of its file.
+type LoadedDocument = (u32, PathBuf, String, SystemTime);
+
+/// How long a stage of the pipeline was blocked on its channels.
+#[derive(Debug, Default, Clone, Copy)]
+struct Blocked {
+    /// Time spent waiting for input from the previous stage.
+    receiving: Duration,
+    /// Time spent waiting for the next stage to make room for output.
+    sending: Duration,
+}
+
+impl AddAssign for Blocked {
+    fn add_assign(&mut self, other: Blocked) {
+        self.receiving += other.receiving;
+        self.sending += other.sending;
+    }
+}
+
+/// A `SyncSender` that keeps track of how long it was blocked because the
+/// channel was full.
+struct TimedSender<T> {
+    sender: SyncSender<T>,
+    blocked: Duration,
+}
+
+impl<T> TimedSender<T> {
+    fn new(sender: SyncSender<T>) -> TimedSender<T> {
+        TimedSender {
+            sender,
+            blocked: Duration::ZERO,
+        }
+    }
+
+    fn send(&mut self, value: T) -> Result<(), SendError<T>> {
+        match self.sender.try_send(value) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(value)) => {
+                let start = Instant::now();
+                let result = self.sender.send(value);
+                self.blocked += start.elapsed();
+                result
+            }
+            Err(TrySendError::Disconnected(value)) => Err(SendError(value)),
+        }
+    }
+}
+
+/// A `Receiver` that keeps track of how long it waited for values.
+struct TimedReceiver<T> {
+    receiver: Receiver<T>,
+    blocked: Duration,
+}
+
+impl<T> TimedReceiver<T> {
+    fn new(receiver: Receiver<T>) -> TimedReceiver<T> {
+        TimedReceiver {
+            receiver,
+            blocked: Duration::ZERO,
+        }
+    }
+}
+
+impl<T> Iterator for TimedReceiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let start = Instant::now();
+        let value = self.receiver.recv().ok();
+        self.blocked += start.elapsed();
+        value
+    }
+}
+
+/// Limits how many documents may be loaded but not yet merged in memory.
+///
+/// Indexes of single documents reach the in-memory merge thread in any
+/// order, and those that arrive before the index of an earlier document wait
+/// there. Bounded channels alone don't limit how many may wait: while one
+/// slow document is tokenized, all the later ones can pile up. So the reader
+/// takes a slot for every document it loads, and the merge thread gives it
+/// back once the document is merged.
+#[derive(Clone)]
+struct Window {
+    /// The number of documents loaded but not yet merged, and whether the
+    /// merge thread has stopped.
+    state: Arc<(Mutex<(usize, bool)>, Condvar)>,
+    size: usize,
+}
+
+impl Window {
+    fn new(size: usize) -> Window {
+        Window {
+            state: Arc::new((Mutex::new((0, false)), Condvar::new())),
+            size,
+        }
+    }
+
+    /// Wait for a free slot and take it. Returns false if the window was
+    /// closed instead.
+    fn acquire(&self) -> bool {
+        let (lock, freed) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.0 >= self.size && !state.1 {
+            state = freed.wait(state).unwrap();
+        }
+        state.0 += 1;
+        !state.1
+    }
+
+    /// Give back the slot of a document that has been merged.
+    fn release(&self) {
+        let (lock, freed) = &*self.state;
+        lock.lock().unwrap().0 -= 1;
+        freed.notify_one();
+    }
+
+    /// Wake the reader for good, once the merge thread stops.
+    fn close(&self) {
+        let (lock, freed) = &*self.state;
+        lock.lock().unwrap().1 = true;
+        freed.notify_all();
+    }
+}
+
+/// Closes a `Window` when dropped, even if the thread holding it panics.
+struct CloseOnDrop(Window);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
 /// Start a thread that loads documents from the filesystem into memory.
 ///
-/// `documents` is a list of filenames to load.
+/// `documents` is a list of filenames to load, and `capacity` is the number of
+/// loaded documents that may wait for the next stage. A document is only
+/// loaded once there is room for it in `window`. Every document loaded is
+/// recorded in `progress`.
 ///
-/// This returns a pair of values: a receiver that receives the documents'
-/// path and content; and a `JoinHandle` that can be used to wait for this
-/// thread to exit and to get the `io::Error` value if anything goes wrong.
+/// This assigns each document an id, in the order of `documents`, the same
+/// way `run_single_threaded` does. It returns a pair of values: a receiver
+/// that receives the documents' id, path, content and modification time; and
+/// a `JoinHandle` that can be used to wait for this thread to exit and to get
+/// the error if anything goes wrong.
 fn start_file_reader_thread(
     documents: Vec<PathBuf>,
-) -> (Receiver<(PathBuf, String)>, JoinHandle<io::Result<()>>) {
-    let (sender, receiver) = channel();
+    capacity: usize,
+    window: Window,
+    progress: Progress,
+) -> (Receiver<LoadedDocument>, JoinHandle<error::Result<Blocked>>) {
+    let (sender, receiver) = sync_channel(capacity);
 
     let handler = spawn(move || {
+        let mut sender = TimedSender::new(sender);
+        let mut waiting = Duration::ZERO;
+        let mut doc_ids = DocIdAllocator::new();
         for filename in documents {
-            let mut f = File::open(filename.clone())?;
-            let mut text = String::new();
-            f.read_to_string(&mut text)?;
-            if sender.send((filename, text)).is_err() {
+            let start = Instant::now();
+            let open = window.acquire();
+            waiting += start.elapsed();
+            if !open {
+                break;
+            }
+            let (text, modified) = read_document(&filename)?;
+            progress.document_read();
+            if sender
+                .send((doc_ids.allocate(), filename, text, modified))
+                .is_err()
+            {
                 break;
             }
         }
-        Ok(())
+        Ok(Blocked {
+            receiving: Duration::ZERO,
+            sending: sender.blocked + waiting,
+        })
     });
 
     (receiver, handler)
 }
 
-/// Start a thread that tokenizes each text and converts it into an in-memory
-/// index. (We assume that every document fits comfortably in memory.)
+/// Start `threads` threads that tokenize each text and convert it into an
+/// in-memory index. (We assume that every document fits comfortably in
+/// memory.)
 ///
-/// `docs` is the stream of documents from the file reader thread.
+/// `docs` is the stream of documents from the file reader thread, and
+/// `analyzer` breaks their text into terms. If `store_text` is true, the text
+/// is kept in the index too. Each document goes to whichever
+/// thread is free first, so the indexes come out in no particular order; each
+/// one is sent along with the id of its document. At most `capacity` indexes
+/// may wait for the next stage. The text tokenized is recorded in `progress`.
 ///
-/// This assigns each document a number. It returns a pair of values: a
-/// receiver, the sequence of in-memory indexes; and a `JoinHandle` that can be
-/// used to wait for this thread to exit. This stage of the pipeline is
-/// infallible (it performs no I/O, so there are no possible errors).
-fn start_file_indexing_thread(
-    docs: Receiver<(PathBuf, String)>,
-) -> (Receiver<InMemoryIndex>, JoinHandle<()>) {
-    let (sender, receiver) = channel();
+/// It returns a pair of values: a receiver, the sequence of in-memory indexes;
+/// and a `JoinHandle` for each thread that can be used to wait for it to exit.
+/// This stage of the pipeline is infallible (it performs no I/O, so there are
+/// no possible errors).
+fn start_file_indexing_threads(
+    docs: Receiver<LoadedDocument>,
+    analyzer: Analyzer,
+    store_text: bool,
+    threads: usize,
+    capacity: usize,
+    progress: Progress,
+) -> (Receiver<(u32, InMemoryIndex)>, Vec<JoinHandle<Blocked>>) {
+    let (sender, receiver) = sync_channel(capacity);
+    let docs = Arc::new(Mutex::new(docs));
+    let analyzer = Arc::new(analyzer);
 
-    let handler = spawn(move || {
-        for (doc_id, (path, text)) in docs.into_iter().enumerate() {
-            // doc_id start from 1
-            let index = InMemoryIndex::from_single_document(doc_id as u32, path, text);
-            if sender.send(index).is_err() {
-                break;
-            }
-        }
-    });
+    let handlers = (0..threads)
+        .map(|_| {
+            let docs = Arc::clone(&docs);
+            let analyzer = Arc::clone(&analyzer);
+            let progress = progress.clone();
+            let mut sender = TimedSender::new(sender.clone());
+            spawn(move || {
+                let mut receiving = Duration::ZERO;
+                loop {
+                    // Only hold the lock while waiting for the next document,
+                    // not while indexing it.
+                    let start = Instant::now();
+                    let next = docs.lock().unwrap().recv();
+                    receiving += start.elapsed();
+                    let Ok((doc_id, path, text, modified)) = next else {
+                        break;
+                    };
+                    let len = text.len() as u64;
+                    let index = InMemoryIndex::from_single_document(
+                        &analyzer, doc_id, path, text, modified, store_text,
+                    );
+                    progress.text_indexed(len);
+                    if sender.send((doc_id, index)).is_err() {
+                        break;
+                    }
+                }
+                Blocked {
+                    receiving,
+                    sending: sender.blocked,
+                }
+            })
+        })
+        .collect();
 
-    (receiver, handler)
+    (receiver, handlers)
 }
 
+/// However many indexes wait to be merged in memory, a large index is only
+/// passed on once it uses at least this fraction of the memory budget, so
+/// that a backlog of waiting indexes doesn't turn every document into a
+/// temporary file of its own.
+const MIN_FLUSH_FRACTION: usize = 4;
+
 /// Start a thread that merges in-memory indexes.
 ///
-/// `file_indexes` receives a stream of indexes from the file indexing thread.
-/// These indexes typically vary a lot in size, since the input documents will
-/// typically be all different sizes.
+/// `indexes` receives a stream of indexes from the file indexing threads,
+/// along with the id of their document. These indexes typically vary a lot
+/// in size, since the input documents will typically be all different sizes.
 ///
 /// The thread created by this function merges those indexes into "large"
-/// indexes and passes these large indexes on to a new channel.
+/// indexes, of about `memory_budget` bytes, and passes these large indexes on
+/// to a new channel, where at most `capacity` of them may wait. Indexes are
+/// merged in order of document id, whatever order they arrive in, so that the
+/// large indexes stay sorted by document id. Indexes that wait for an earlier
+/// one count against `memory_budget` too, so a large index may be passed on
+/// early to make room for them, though never before it reaches
+/// `1 / MIN_FLUSH_FRACTION` of the budget. Every index merged frees its document's slot
+/// in `window`, and the window is closed when the thread exits.
 ///
 /// This returns a pair: a receiver, the sequence of large indexes produced by
 /// merging the input indexes; and a `JoinHandle` that can be used to wait for
 /// this thread to exit. This stage of the pipeline is infallible (it performs
 /// no I/O).
 fn start_in_memory_merge_thread(
-    indexes: Receiver<InMemoryIndex>,
-) -> (Receiver<InMemoryIndex>, JoinHandle<()>) {
-    let (sender, receiver) = channel();
+    indexes: Receiver<(u32, InMemoryIndex)>,
+    analyzer: String,
+    memory_budget: usize,
+    capacity: usize,
+    window: Window,
+) -> (Receiver<InMemoryIndex>, JoinHandle<Blocked>) {
+    let (sender, receiver) = sync_channel(capacity);
 
     let handle = spawn(move || {
-        let mut accumulated_index = InMemoryIndex::new();
-        for i in indexes {
-            accumulated_index.merge(i);
-            if accumulated_index.is_large() {
-                if sender.send(accumulated_index).is_err() {
-                    return;
+        let window = CloseOnDrop(window);
+        let mut indexes = TimedReceiver::new(indexes);
+        let mut sender = TimedSender::new(sender);
+        let mut accumulated_index = InMemoryIndex::with_analyzer(&analyzer);
+        // Indexes that arrived before some index for an earlier document,
+        // and the heap memory they use.
+        let mut pending = BTreeMap::new();
+        let mut pending_bytes = 0;
+        let mut next_id = DocIdAllocator::FIRST_ID;
+        'receive: for (doc_id, i) in &mut indexes {
+            pending_bytes += i.heap_bytes;
+            pending.insert(doc_id, i);
+            while let Some(i) = pending.remove(&next_id) {
+                next_id += 1;
+                pending_bytes -= i.heap_bytes;
+                accumulated_index.merge(i);
+                window.0.release();
+                let threshold = memory_budget
+                    .saturating_sub(pending_bytes)
+                    .max(memory_budget / MIN_FLUSH_FRACTION);
+                if accumulated_index.exceeds(threshold) {
+                    if sender.send(accumulated_index).is_err() {
+                        accumulated_index = InMemoryIndex::with_analyzer(&analyzer);
+                        break 'receive;
+                    }
+                    accumulated_index = InMemoryIndex::with_analyzer(&analyzer);
                 }
-                accumulated_index = InMemoryIndex::new();
             }
         }
         if !accumulated_index.is_empty() {
             let _ = sender.send(accumulated_index);
         }
+        Blocked {
+            receiving: indexes.blocked,
+            sending: sender.blocked,
+        }
     });
 
     (receiver, handle)
@@ -148,7 +434,9 @@ fn start_in_memory_merge_thread(
 /// Start a thread that saves large indexes to temporary files.
 ///
 /// This thread generates a meaningless unique filename for each index in
-/// `big_indexes`, saves the data, and passes the filename on to a new channel.
+/// `big_indexes`, saves the data, and passes the filename on to a new channel,
+/// where at most `capacity` filenames may wait. Every file written is
+/// recorded in `progress`.
 ///
 /// This returns a pair: a receiver that receives the filenames; and a
 /// `JoinHandle` that can be used to wait for this thread to exit and receive
@@ -156,89 +444,220 @@ fn start_in_memory_merge_thread(
 fn start_index_writer_thread(
     big_indexes: Receiver<InMemoryIndex>,
     output_dir: &Path,
-) -> (Receiver<PathBuf>, JoinHandle<io::Result<()>>) {
-    let (sender, receiver) = channel();
+    capacity: usize,
+    progress: Progress,
+) -> (Receiver<PathBuf>, JoinHandle<error::Result<Blocked>>) {
+    let (sender, receiver) = sync_channel(capacity);
 
     let mut tmp_dir = TmpDir::new(output_dir);
     let handle = spawn(move || {
-        for i in big_indexes {
-            println!("word count: {}", i.word_count);
+        let mut big_indexes = TimedReceiver::new(big_indexes);
+        let mut sender = TimedSender::new(sender);
+        for i in &mut big_indexes {
             let file = write_index_to_tmp_file(i, &mut tmp_dir)?;
+            progress.tmp_file_written();
             if sender.send(file).is_err() {
                 break;
             }
         }
-        Ok(())
+        Ok(Blocked {
+            receiving: big_indexes.blocked,
+            sending: sender.blocked,
+        })
     });
 
     (receiver, handle)
 }
 
-fn merge_index_files(files: Receiver<PathBuf>, output_dir: &Path) -> io::Result<()> {
-    let mut merge = FileMerge::new(output_dir);
-    for file in files {
+/// Merge the temporary files received from `files`.
+///
+/// Merges run on a pool of `opts.merge_threads` threads, so this only blocks
+/// while waiting for files. It returns the `FileMerge` without finishing it:
+/// the final index must only replace the previous one once every stage of
+/// the pipeline has succeeded.
+fn merge_index_files(
+    files: Receiver<PathBuf>,
+    output_dir: &Path,
+    progress: &Progress,
+    opts: &Opts,
+) -> error::Result<(FileMerge, Blocked)> {
+    let mut files = TimedReceiver::new(files);
+    let mut merge = FileMerge::new(
+        output_dir,
+        opts.merge_streams,
+        opts.merge_threads,
+        progress.clone(),
+    );
+    for file in &mut files {
         merge.add_file(file)?;
     }
-    merge.finish()
+    Ok((
+        merge,
+        Blocked {
+            receiving: files.blocked,
+            sending: Duration::ZERO,
+        },
+    ))
 }
 
-fn run_pipeline(documents: Vec<PathBuf>, output_dir: PathBuf) -> io::Result<()> {
+/// Print how long each stage of the pipeline was blocked, to help choose
+/// channel capacities. Times of the indexing stage are summed over its
+/// threads.
+fn report_blocked_times(stages: &[(&str, Blocked)]) {
+    println!("time blocked per pipeline stage (waiting for input / for room for output):");
+    for (name, blocked) in stages {
+        println!(
+            "  {:<16} {:>9.3}s / {:>9.3}s",
+            name,
+            blocked.receiving.as_secs_f64(),
+            blocked.sending.as_secs_f64()
+        );
+    }
+}
+
+/// The time each stage of the pipeline was blocked, by name of stage.
+type StageTimes = Vec<(&'static str, Blocked)>;
+
+/// Create an inverted index for the given list of `documents`, storing it in
+/// the specified `output_dir`, on a pipeline of threads.
+///
+/// Everything that is done is recorded in `progress`. Returns how long each
+/// stage of the pipeline was blocked.
+fn run_pipeline(
+    documents: Vec<PathBuf>,
+    output_dir: PathBuf,
+    analyzer: Analyzer,
+    progress: &Progress,
+    opts: &Opts,
+) -> error::Result<StageTimes> {
+    let index_threads = match opts.index_threads {
+        Some(n) => n,
+        None => available_parallelism().map_or(1, |n| n.get()),
+    };
+
     // Launch all five stages of the pipeline.
-    let (texts, h1) = start_file_reader_thread(documents);
-    let (pints, h2) = start_file_indexing_thread(texts);
-    let (gallons, h3) = start_in_memory_merge_thread(pints);
-    let (files, h4) = start_index_writer_thread(gallons, &output_dir);
-    let result = merge_index_files(files, &output_dir);
+    let analyzer_name = analyzer.name().to_string();
+    let window = Window::new(opts.index_queue);
+    let (texts, h1) =
+        start_file_reader_thread(documents, opts.text_queue, window.clone(), progress.clone());
+    let (pints, h2) = start_file_indexing_threads(
+        texts,
+        analyzer,
+        opts.store_text,
+        index_threads,
+        opts.index_queue,
+        progress.clone(),
+    );
+    let (gallons, h3) = start_in_memory_merge_thread(
+        pints,
+        analyzer_name,
+        opts.memory_budget,
+        opts.large_index_queue,
+        window,
+    );
+    let (files, h4) =
+        start_index_writer_thread(gallons, &output_dir, opts.file_queue, progress.clone());
+    let result = merge_index_files(files, &output_dir, progress, opts);
 
     // Wait for threads to finish, holding on to any errors that they encounter.
     let r1 = h1.join().unwrap();
-    h2.join().unwrap();
-    h3.join().unwrap();
+    let mut indexing = Blocked::default();
+    for h in h2 {
+        indexing += h.join().unwrap();
+    }
+    let merging = h3.join().unwrap();
     let r4 = h4.join().unwrap();
 
-    // Return the first error encountered, if any.
+    // Return the first error encountered, if any, before the final merge
+    // replaces the previous index with one that misses documents.
     // (As it happens, h2 and h3 can't fail: those threads
     // are pure in-memory data processing.)
-    r1?;
-    r4?;
-    result
-}
-
-/// Given some paths, generate the complete list of text files to index. We check
-/// on disk whether the path is the name of a file or a directory; for
-/// directories, all .txt files immediately under the directory are indexed.
-/// Relative paths are fine.
-///
-/// It's an error if any of the `args` is not a valid path to an existing file
-/// or directory.
-fn expand_filename_arguments(args: Vec<String>) -> io::Result<Vec<PathBuf>> {
-    let mut filenames = vec![];
-    for arg in args {
-        let path = PathBuf::from(arg);
-        if path.metadata()?.is_dir() {
-            for entry in path.read_dir()? {
-                let entry = entry?;
-                if entry.file_type()?.is_file() {
-                    filenames.push(entry.path());
-                }
-            }
-        } else {
-            filenames.push(path);
-        }
-    }
-    Ok(filenames)
+    let read = r1?;
+    let write = r4?;
+    let (merge, merging_files) = result?;
+    merge.finish()?;
+    Ok(vec![
+        ("read", read),
+        ("index", indexing),
+        ("merge in memory", merging),
+        ("write", write),
+        ("merge files", merging_files),
+    ])
 }
 
 /// Generate an index for a bunch of text files.
-fn run(filenames: Vec<String>, single_threaded: bool) -> io::Result<()> {
+fn run(opts: &Opts) -> error::Result<()> {
     let output_dir = PathBuf::from(".");
-    let documents = expand_filename_arguments(filenames)?;
+    let analyzer = Analyzer::by_name(&opts.analyzer)
+        .ok_or_else(|| IndexError::UnknownAnalyzer(opts.analyzer.clone()))?;
+    // Record every document by its canonical path, so that the index names
+    // the same files wherever it is used from.
+    let documents = expand_filename_arguments(&opts.filenames)?
+        .into_iter()
+        .map(|path| fs::canonicalize(&path).map_err(IndexError::file_io(&path)))
+        .collect::<error::Result<Vec<_>>>()?;
+
+    let mut bytes_total = 0;
+    for document in &documents {
+        bytes_total += fs::metadata(document)
+            .map_err(IndexError::file_io(document))?
+            .len();
+    }
+    let progress = Progress::new(documents.len() as u64, bytes_total);
+    let reporter = if opts.quiet {
+        None
+    } else if io::stderr().is_terminal() {
+        Some(ProgressReporter::start(
+            progress.clone(),
+            ProgressStyle::Bar,
+        ))
+    } else {
+        Some(ProgressReporter::start(
+            progress.clone(),
+            ProgressStyle::JsonLines,
+        ))
+    };
 
-    if single_threaded {
-        run_single_threaded(documents, output_dir)
+    let result = if opts.single_threaded {
+        run_single_threaded(documents, output_dir, analyzer, &progress, opts).map(|()| vec![])
     } else {
-        run_pipeline(documents, output_dir)
+        run_pipeline(documents, output_dir, analyzer, &progress, opts)
+    };
+
+    if let Some(reporter) = reporter {
+        reporter.finish();
+    }
+    let stages = result?;
+    if !opts.quiet && !stages.is_empty() {
+        report_blocked_times(&stages);
     }
+    Ok(())
+}
+
+/// Parse a number of bytes with an optional unit, such as `4096`, `512MiB` or
+/// `2GB`. Units are not case-sensitive.
+fn parse_byte_size(arg: &str) -> Result<usize, String> {
+    let arg = arg.trim();
+    let split = arg
+        .find(|ch: char| !ch.is_ascii_digit())
+        .unwrap_or(arg.len());
+    let (number, unit) = arg.split_at(split);
+    let number: usize = number
+        .parse()
+        .map_err(|_| format!("invalid size {:?}", arg))?;
+    let multiplier: usize = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "kib" | "k" => 1 << 10,
+        "mb" => 1000 * 1000,
+        "mib" | "m" => 1 << 20,
+        "gb" => 1000 * 1000 * 1000,
+        "gib" | "g" => 1 << 30,
+        _ => return Err(format!("unknown unit {:?} in size {:?}", unit.trim(), arg)),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size {:?} is too large", arg))
 }
 
 #[derive(Parser)]
@@ -246,14 +665,144 @@ struct Opts {
     #[arg(short, long, default_value_t = false, help = "Default false")]
     single_threaded: bool,
 
+    #[arg(
+        short,
+        long,
+        default_value = Analyzer::STANDARD,
+        help = "Analyzer used to break documents into terms (standard, whitespace)"
+    )]
+    analyzer: String,
+
+    #[arg(
+        long,
+        help = "Store the text of every document in the index, compressed, so search doesn't need the original files"
+    )]
+    store_text: bool,
+
+    #[arg(
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Number of threads that tokenize documents [default: number of CPUs]"
+    )]
+    index_threads: Option<usize>,
+
+    #[arg(
+        long,
+        default_value = "1GiB",
+        value_parser = parse_byte_size,
+        help = "Memory used by an in-memory index before it is written to disk, e.g. 512MiB or 2GB"
+    )]
+    memory_budget: usize,
+
+    #[arg(
+        long,
+        default_value_t = 64,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Number of loaded documents that may wait to be tokenized"
+    )]
+    text_queue: usize,
+
+    #[arg(
+        long,
+        default_value_t = 256,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Number of documents that may be loaded but not yet merged in memory, including indexes of single documents that wait for an earlier one"
+    )]
+    index_queue: usize,
+
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Number of large indexes (of up to --memory-budget each) that may wait to be written"
+    )]
+    large_index_queue: usize,
+
+    #[arg(
+        long,
+        default_value_t = 16,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Number of temporary index files that may wait to be merged"
+    )]
+    file_queue: usize,
+
+    #[arg(
+        long,
+        default_value_t = DEFAULT_NSTREAMS,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(2..),
+        help = "Number of temporary index files merged at a time, at most"
+    )]
+    merge_streams: usize,
+
+    #[arg(
+        long,
+        default_value_t = 2,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Number of threads that merge temporary index files in the background (ignored with --single-threaded)"
+    )]
+    merge_threads: usize,
+
+    #[arg(
+        short,
+        long,
+        help = "Don't report progress (shown as a bar on a terminal, as JSON lines on stderr otherwise)"
+    )]
+    quiet: bool,
+
     #[arg(required = true)]
     filenames: Vec<String>,
 }
 
 fn main() {
     let opts = Opts::parse();
-    match run(opts.filenames, opts.single_threaded) {
-        Ok(()) => {}
-        Err(err) => println!("error: {}", err),
+    init_logging();
+    if let Err(err) = run(&opts) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_sizes_with_every_unit() {
+        assert_eq!(parse_byte_size("4096"), Ok(4096));
+        assert_eq!(parse_byte_size("7b"), Ok(7));
+        assert_eq!(parse_byte_size("2kb"), Ok(2000));
+        assert_eq!(parse_byte_size("2kib"), Ok(2048));
+        assert_eq!(parse_byte_size("2k"), Ok(2048));
+        assert_eq!(parse_byte_size("3mb"), Ok(3_000_000));
+        assert_eq!(parse_byte_size("3mib"), Ok(3 << 20));
+        assert_eq!(parse_byte_size("3m"), Ok(3 << 20));
+        assert_eq!(parse_byte_size("1gb"), Ok(1_000_000_000));
+        assert_eq!(parse_byte_size("1gib"), Ok(1 << 30));
+        assert_eq!(parse_byte_size("1g"), Ok(1 << 30));
+    }
+
+    #[test]
+    fn byte_size_units_ignore_case_and_spaces() {
+        assert_eq!(parse_byte_size("512MiB"), Ok(512 << 20));
+        assert_eq!(parse_byte_size("512mIb"), Ok(512 << 20));
+        assert_eq!(parse_byte_size("2GB"), Ok(2_000_000_000));
+        assert_eq!(parse_byte_size(" 512 MiB "), Ok(512 << 20));
+        assert_eq!(parse_byte_size("10 B"), Ok(10));
+    }
+
+    #[test]
+    fn bad_byte_sizes_are_errors() {
+        for arg in ["", "MiB", "-1", "1.5GiB", "5 5", "12TB", "1kbit", "GiB 2"] {
+            assert!(parse_byte_size(arg).is_err(), "{:?}", arg);
+        }
+        assert_eq!(
+            parse_byte_size("12TB"),
+            Err("unknown unit \"TB\" in size \"12TB\"".to_string())
+        );
+        let max = usize::MAX.to_string();
+        assert_eq!(parse_byte_size(&max), Ok(usize::MAX));
+        assert!(parse_byte_size(&format!("{}k", max)).is_err());
+        assert!(parse_byte_size(&format!("{}0", max)).is_err());
+        assert!(parse_byte_size(&format!("{}GiB", usize::MAX >> 29)).is_err());
     }
 }
@@ -1,19 +1,360 @@
-use std::io;
+mod common;
 
-use clap::Parser;
-use inverted_index_concurrency::index::InMemoryIndex;
+use std::{fs, io, process};
+
+use clap::{Parser, ValueEnum};
+use inverted_index_concurrency::{
+    analysis::Analyzer,
+    error::{IndexError, Result},
+    fingerprint::DiskState,
+    highlight::{json_string, Ansi, Color, Highlighter, Html, JsonSpans, Markdown},
+    query::Query,
+    results::SearchHit,
+    score::Bm25,
+    searcher::IndexSearcher,
+    snippet::{snippets, Snippet},
+};
+
+use common::init_logging;
 
 #[derive(Parser)]
 struct Opts {
     #[arg(short, long, required = true, help = "Specify index file path")]
     index_file: String,
-    #[arg(short, long, required = true, help = "Specify search term")]
+    #[arg(
+        short,
+        long,
+        visible_alias = "query",
+        required = true,
+        help = "Specify search term or query, e.g. \"rust AND (async OR tokio) NOT unsafe\""
+    )]
     term: String,
+    #[arg(
+        short = 'k',
+        long,
+        default_value_t = 10,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Show the k best documents"
+    )]
+    top_k: usize,
+    #[arg(
+        long,
+        default_value_t = Bm25::default().k1,
+        value_parser = parse_k1,
+        help = "BM25 term frequency saturation, at least 0"
+    )]
+    k1: f32,
+    #[arg(
+        long,
+        default_value_t = Bm25::default().b,
+        value_parser = parse_b,
+        help = "BM25 document length normalization, from 0 to 1"
+    )]
+    b: f32,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Show at most N snippets of each document around its matches, instead of the whole document"
+    )]
+    snippets: Option<usize>,
+    #[arg(
+        long,
+        value_name = "WORDS",
+        default_value_t = 5,
+        requires = "snippets",
+        help = "Number of words shown on each side of a match in a snippet"
+    )]
+    context: u32,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Ansi,
+        help = "How matches are highlighted; json prints one object per document with the spans of its matches"
+    )]
+    format: OutputFormat,
+    #[arg(
+        long,
+        default_value = "red",
+        help = "Color of matches with --format ansi (black, red, green, yellow, blue, magenta, cyan, white)"
+    )]
+    color: Color,
 }
 
-fn main() -> io::Result<()> {
-    let opts = Opts::parse();
-    let index = InMemoryIndex::from_index_file(opts.index_file)?;
-    index.search(&opts.term)?;
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Ansi,
+    Html,
+    Markdown,
+    Json,
+}
+
+fn run(opts: &Opts) -> Result<()> {
+    let index = IndexSearcher::open(&opts.index_file)?;
+    // Analyze the query the same way the documents were analyzed.
+    let analyzer = Analyzer::by_name(index.analyzer())
+        .ok_or_else(|| IndexError::UnknownAnalyzer(index.analyzer().to_string()))?;
+    let query = Query::parse(&opts.term, &analyzer)?;
+    let ranking = Bm25 {
+        k1: opts.k1,
+        b: opts.b,
+    };
+    let results = index.search(&query, &ranking, opts.top_k)?;
+    // With --format json, no documents is no output.
+    if results.is_empty() && opts.format != OutputFormat::Json {
+        println!("no documents match {}", opts.term);
+        return Ok(());
+    }
+    let highlighter = match opts.format {
+        OutputFormat::Ansi => Highlighter::new(Ansi {
+            color: opts.color,
+            bold: false,
+        }),
+        OutputFormat::Html => Highlighter::new(Html),
+        OutputFormat::Markdown => Highlighter::new(Markdown),
+        OutputFormat::Json => Highlighter::new(JsonSpans),
+    };
+    for hit in &results {
+        let DocumentText { text, stored, disk } = document_text(&index, hit)?;
+        let snippets = match (&text, opts.snippets) {
+            (Some(text), Some(count)) => {
+                Some(snippets(text, &analyzer, &hit.matches, count, opts.context))
+            }
+            _ => None,
+        };
+        if opts.format == OutputFormat::Json {
+            println!(
+                "{}",
+                format_json_hit(
+                    &highlighter,
+                    hit,
+                    text.as_deref(),
+                    disk,
+                    snippets.as_deref()
+                )
+            );
+            continue;
+        }
+        let shown = match (&text, snippets) {
+            (None, _) => String::new(),
+            (Some(text), Some(snippets)) => snippets
+                .iter()
+                .map(|snippet| format_snippet(&highlighter, text, snippet))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            (Some(text), None) => highlighter.highlight(text, 0, &hit.matches),
+        };
+        let note = match (disk, stored) {
+            (DiskState::Unchanged, _) => "",
+            (DiskState::Modified, true) => {
+                " [file changed since indexing, showing the indexed text]"
+            }
+            (DiskState::Missing, true) => {
+                " [file deleted since indexing, showing the indexed text]"
+            }
+            (DiskState::Modified, false) => " [file changed since indexing, matches may be off]",
+            (DiskState::Missing, false) => " [file deleted since indexing]",
+        };
+        println!(
+            "\n{} \n{}",
+            format_header(opts.format, &highlighter, hit, note),
+            shown
+        );
+    }
     Ok(())
 }
+
+/// The text shown for a search hit.
+struct DocumentText {
+    /// The text of the document, or `None` if the index doesn't store it and
+    /// its file is gone.
+    text: Option<String>,
+    /// True if `text` is the copy stored in the index.
+    stored: bool,
+    /// How the file on disk compares with its fingerprint in the index.
+    disk: DiskState,
+}
+
+/// The text of the document of `hit`: the copy stored in the index if it has
+/// one, otherwise the file on disk.
+fn document_text(index: &IndexSearcher, hit: &SearchHit) -> Result<DocumentText> {
+    let doc = index
+        .document(hit.doc_id)?
+        .ok_or(IndexError::DocumentMissing { id: hit.doc_id })?;
+    if index.has_stored_text() {
+        if let Some(text) = index.stored_text(hit.doc_id)? {
+            // The stored copy is shown either way, so a file that can't be
+            // read only counts as modified.
+            let disk = doc
+                .fingerprint
+                .disk_state(&doc.path)
+                .unwrap_or(DiskState::Modified);
+            return Ok(DocumentText {
+                text: Some(text),
+                stored: true,
+                disk,
+            });
+        }
+    }
+    let disk = doc
+        .fingerprint
+        .disk_state(&doc.path)
+        .map_err(IndexError::file_io(&doc.path))?;
+    let text = match fs::read_to_string(&doc.path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(DocumentText {
+                text: None,
+                stored: false,
+                disk: DiskState::Missing,
+            })
+        }
+        Err(err) => return Err(IndexError::file_io(&doc.path)(err)),
+    };
+    Ok(DocumentText {
+        text: Some(text),
+        stored: false,
+        disk,
+    })
+}
+
+/// The line above the text of a search hit: its path, score and `note`. With
+/// HTML or Markdown, the path is in bold, and all of it is escaped by
+/// `highlighter`.
+fn format_header(
+    format: OutputFormat,
+    highlighter: &Highlighter,
+    hit: &SearchHit,
+    note: &str,
+) -> String {
+    let rest = format!(" (score {:.4}){}:", hit.score, note);
+    let path = hit.path.to_string_lossy();
+    match format {
+        OutputFormat::Html => format!(
+            "<b>{}</b>{}",
+            highlighter.escape(&path),
+            highlighter.escape(&rest)
+        ),
+        OutputFormat::Markdown => format!(
+            "**{}**{}",
+            highlighter.escape(&path),
+            highlighter.escape(&rest)
+        ),
+        OutputFormat::Ansi | OutputFormat::Json => format!("{:?}{}", hit.path, rest),
+    }
+}
+
+/// A snippet on a single line, with its matches highlighted, and ellipses
+/// where the document goes on.
+fn format_snippet(highlighter: &Highlighter, document: &str, snippet: &Snippet) -> String {
+    let text = highlighter.highlight(snippet.text(document), snippet.start, &snippet.matches);
+    let text = text.replace(['\n', '\r', '\t'], " ");
+    format!(
+        "{}{}{}",
+        if snippet.truncated_start { "... " } else { "" },
+        text.trim(),
+        if snippet.truncated_end { " ..." } else { "" }
+    )
+}
+
+/// A search hit as a single line of JSON. The spans of the whole document,
+/// or of every snippet, are rendered by `highlighter`; there are none if
+/// there is no `document` to show. `disk` says how the file on disk compares
+/// with what was indexed.
+fn format_json_hit(
+    highlighter: &Highlighter,
+    hit: &SearchHit,
+    document: Option<&str>,
+    disk: DiskState,
+    snippets: Option<&[Snippet]>,
+) -> String {
+    let file = match disk {
+        DiskState::Unchanged => "unchanged",
+        DiskState::Modified => "modified",
+        DiskState::Missing => "missing",
+    };
+    let head = format!(
+        "{{\"doc_id\":{},\"path\":{},\"score\":{},\"file\":\"{}\"",
+        hit.doc_id,
+        json_string(&hit.path.to_string_lossy()),
+        hit.score,
+        file
+    );
+    let Some(document) = document else {
+        return format!("{}}}", head);
+    };
+    match snippets {
+        Some(snippets) => {
+            let snippets: Vec<String> = snippets
+                .iter()
+                .map(|snippet| {
+                    let text = snippet.text(document);
+                    format!(
+                        "{{\"start\":{},\"end\":{},\"text\":{},\"spans\":{}}}",
+                        snippet.start,
+                        snippet.end,
+                        json_string(text),
+                        highlighter.highlight(text, snippet.start, &snippet.matches)
+                    )
+                })
+                .collect();
+            format!("{},\"snippets\":[{}]}}", head, snippets.join(","))
+        }
+        None => format!(
+            "{},\"spans\":{}}}",
+            head,
+            highlighter.highlight(document, 0, &hit.matches)
+        ),
+    }
+}
+
+/// Parse the BM25 `k1` parameter, which must be a finite number of at
+/// least 0, or scores could be `NaN`.
+fn parse_k1(arg: &str) -> std::result::Result<f32, String> {
+    let k1: f32 = arg
+        .parse()
+        .map_err(|_| format!("invalid number {:?}", arg))?;
+    if !(0.0..=f32::MAX).contains(&k1) {
+        return Err(format!("{} is not a number of at least 0", arg));
+    }
+    Ok(k1)
+}
+
+/// Parse the BM25 `b` parameter, which must be from 0 to 1.
+fn parse_b(arg: &str) -> std::result::Result<f32, String> {
+    let b: f32 = arg
+        .parse()
+        .map_err(|_| format!("invalid number {:?}", arg))?;
+    if !(0.0..=1.0).contains(&b) {
+        return Err(format!("{} is not in the range 0 to 1", arg));
+    }
+    Ok(b)
+}
+
+fn main() {
+    let opts = Opts::parse();
+    init_logging();
+    if let Err(err) = run(&opts) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bm25_parameters_must_be_in_range() {
+        assert_eq!(parse_k1("0"), Ok(0.0));
+        assert_eq!(parse_k1("2.5"), Ok(2.5));
+        for arg in ["-1", "NaN", "inf", "x"] {
+            assert!(parse_k1(arg).is_err(), "{}", arg);
+        }
+        assert_eq!(parse_b("0"), Ok(0.0));
+        assert_eq!(parse_b("1"), Ok(1.0));
+        for arg in ["-0.1", "1.5", "NaN", ""] {
+            assert!(parse_b(arg).is_err(), "{}", arg);
+        }
+    }
+}
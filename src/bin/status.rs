@@ -0,0 +1,94 @@
+mod common;
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use clap::Parser;
+use inverted_index_concurrency::{
+    error::Result, files::expand_filename_arguments, fingerprint::DiskState,
+    searcher::IndexSearcher,
+};
+
+use common::init_logging;
+
+#[derive(Parser)]
+struct Opts {
+    #[arg(short, long, required = true, help = "Specify index file path")]
+    index_file: String,
+
+    #[arg(
+        help = "Files and directories the index should cover, as given to create; \
+                files in them that the index doesn't have are listed as new. \
+                Without them, only modified and deleted files are listed"
+    )]
+    filenames: Vec<String>,
+}
+
+/// `path` made absolute, with symbolic links and `..` resolved, the way
+/// `create` records the paths of documents. A path that doesn't exist is kept
+/// as it is.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Compare the documents of the index with the files on disk and print the
+/// ones that changed. Returns whether the index is up to date.
+fn run(opts: &Opts) -> Result<bool> {
+    let index = IndexSearcher::open(&opts.index_file)?;
+    let files = expand_filename_arguments(&opts.filenames)?;
+
+    let mut indexed: HashSet<PathBuf> = HashSet::new();
+    let (mut modified, mut deleted, mut unreadable, mut unchanged) = (0, 0, 0, 0);
+    for doc in index.documents()? {
+        // A file that can't be checked may have changed; it doesn't stop the
+        // check of the others.
+        match doc.fingerprint.disk_state(&doc.path) {
+            Ok(DiskState::Unchanged) => unchanged += 1,
+            Ok(DiskState::Modified) => {
+                println!("modified: {}", doc.path.display());
+                modified += 1;
+            }
+            Ok(DiskState::Missing) => {
+                println!("deleted:  {}", doc.path.display());
+                deleted += 1;
+            }
+            Err(err) => {
+                println!("unreadable: {}: {}", doc.path.display(), err);
+                unreadable += 1;
+            }
+        }
+        indexed.insert(doc.path);
+    }
+
+    let mut new = 0;
+    for file in files
+        .iter()
+        .filter(|file| !indexed.contains(&canonical(file)))
+    {
+        println!("new:      {}", file.display());
+        new += 1;
+    }
+
+    println!(
+        "{}: {} new, {} modified, {} deleted, {} unreadable, {} unchanged",
+        opts.index_file, new, modified, deleted, unreadable, unchanged
+    );
+    Ok(new + modified + deleted + unreadable == 0)
+}
+
+fn main() {
+    let opts = Opts::parse();
+    init_logging();
+    match run(&opts) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("{}: error: {}", opts.index_file, err);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use std::process;
+
+use clap::Parser;
+use inverted_index_concurrency::{error::Result, verify::verify_index_file};
+
+use common::init_logging;
+
+#[derive(Parser)]
+struct Opts {
+    #[arg(short, long, required = true, help = "Specify index file path")]
+    index_file: String,
+}
+
+/// Check the index file and print what is damaged. Returns whether the file
+/// is intact.
+fn run(opts: &Opts) -> Result<bool> {
+    let damage = verify_index_file(&opts.index_file)?;
+    if damage.is_empty() {
+        println!("{}: ok", opts.index_file);
+        return Ok(true);
+    }
+    for d in &damage {
+        println!("{}: {}", opts.index_file, d);
+    }
+    Ok(false)
+}
+
+fn main() {
+    let opts = Opts::parse();
+    init_logging();
+    match run(&opts) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("{}: error: {}", opts.index_file, err);
+            process::exit(1);
+        }
+    }
+}
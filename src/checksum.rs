@@ -0,0 +1,49 @@
+//! CRC32C checksums of index files.
+//!
+//! Every section of an index file after the main data (the table of entries,
+//! the table of documents and the term index) has a checksum in the header,
+//! and the header has a checksum of its own. Within the main data, the hits of
+//! every term and the data of every document are separate blocks, each with a
+//! checksum stored in its entry or record. A reader can then tell exactly
+//! which term or document is damaged.
+
+use std::io::{self, Read};
+
+/// The CRC32C checksum of `data`.
+pub fn checksum(data: &[u8]) -> u32 {
+    crc32c::crc32c(data)
+}
+
+/// Extend the checksum `crc` of some data with more data.
+pub fn extend_checksum(crc: u32, data: &[u8]) -> u32 {
+    crc32c::crc32c_append(crc, data)
+}
+
+/// A reader that computes the checksum of everything read through it.
+pub(crate) struct ChecksumReader<R> {
+    inner: R,
+    crc: u32,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub(crate) fn new(inner: R) -> ChecksumReader<R> {
+        ChecksumReader { inner, crc: 0 }
+    }
+
+    /// The checksum of the data read so far.
+    pub(crate) fn checksum(&self) -> u32 {
+        self.crc
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc = extend_checksum(self.crc, &buf[..n]);
+        Ok(n)
+    }
+}
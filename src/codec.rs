@@ -0,0 +1,349 @@
+//! Compressed posting lists.
+//!
+//! The hits of a term are stored as a list of postings, one per document, in
+//! increasing order of document id. Every number is a varint: 7 bits per
+//! byte, least significant first, with the high bit set on every byte but
+//! the last. Since most numbers are small deltas, most take a single byte.
+//!
+//! A posting is laid out as follows:
+//! - The document id, as a delta from the document id of the previous
+//!   posting in the list (or from 0, for the first posting).
+//! - The number of occurrences of the term in the document.
+//! - For every occurrence, in increasing order: the token ordinal and the
+//!   start offset, both as deltas from the previous occurrence (or from 0),
+//!   and the length of the token (`end_pos - start_pos`).
+//!
+//! Only the first posting of a list depends on what precedes it, so two lists
+//! can be concatenated by re-encoding the first document id of the second
+//! list; see `rebase_posting_list`.
+//!
+//! This module is the only one that knows how postings are encoded. Everything
+//! else deals in `Posting`s.
+
+use crate::{
+    error::{IndexError, Result},
+    index::{Posting, TokenPos},
+};
+
+/// Append `value` to `buf` as a varint.
+fn write_varint(buf: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Read a varint from the front of `data`, and advance `data` past it.
+/// Returns `None` if `data` does not start with a valid varint: one that is
+/// truncated, or longer than the 5 bytes a `u32` takes.
+fn read_varint(data: &mut &[u8]) -> Option<u32> {
+    let mut value: u32 = 0;
+    for (i, &byte) in data.iter().enumerate().take(5) {
+        // The 5th byte holds the top 4 bits; anything more doesn't fit.
+        if i == 4 && byte & 0x70 != 0 {
+            return None;
+        }
+        value |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            *data = &data[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// The delta from `prev_doc`, the document id of the previous posting of a
+/// list (if any), to `doc_id`.
+fn doc_delta(prev_doc: Option<u32>, doc_id: u32) -> Result<u32> {
+    match prev_doc {
+        None => Ok(doc_id),
+        Some(prev) if doc_id > prev => Ok(doc_id - prev),
+        Some(prev) => Err(IndexError::UnsortedPostings {
+            doc_id,
+            prev_doc: prev,
+        }),
+    }
+}
+
+/// Append `posting` to the posting list in `buf`, whose last posting is for
+/// the document `prev_doc` (or which is empty, if `prev_doc` is `None`).
+///
+/// The positions of `posting` must be in increasing order, each ending no
+/// earlier than it starts, and its document id must be greater than
+/// `prev_doc`. Otherwise nothing is appended.
+pub fn encode_posting(buf: &mut Vec<u8>, prev_doc: Option<u32>, posting: &Posting) -> Result<()> {
+    let delta = doc_delta(prev_doc, posting.doc_id)?;
+    let unsorted = || IndexError::UnsortedPositions {
+        doc_id: posting.doc_id,
+    };
+    let len = buf.len();
+    write_varint(buf, delta);
+    write_varint(buf, posting.positions.len() as u32);
+    let (mut position, mut start) = (0, 0);
+    for pos in &posting.positions {
+        let deltas = (
+            pos.position.checked_sub(position),
+            pos.start_pos.checked_sub(start),
+            pos.end_pos.checked_sub(pos.start_pos),
+        );
+        let (Some(position_delta), Some(start_delta), Some(length)) = deltas else {
+            buf.truncate(len);
+            return Err(unsorted());
+        };
+        write_varint(buf, position_delta);
+        write_varint(buf, start_delta);
+        write_varint(buf, length);
+        position = pos.position;
+        start = pos.start_pos;
+    }
+    Ok(())
+}
+
+/// Prepare the encoded posting list `data`, found at `offset` in an index
+/// file, to be appended to a posting list whose last posting is for the
+/// document `prev_doc`.
+///
+/// Returns the re-encoded first document id of `data`, which must be written
+/// in place of the original, followed by the rest of `data`, unchanged.
+pub fn rebase_posting_list(
+    data: &[u8],
+    offset: u64,
+    prev_doc: Option<u32>,
+) -> Result<(Vec<u8>, &[u8])> {
+    let mut rest = data;
+    let first_doc = read_varint(&mut rest).ok_or_else(|| corrupt_postings(offset))?;
+    let mut head = Vec::with_capacity(5);
+    write_varint(&mut head, doc_delta(prev_doc, first_doc)?);
+    Ok((head, rest))
+}
+
+/// An iterator over the postings of an encoded posting list, decoding them
+/// lazily, one at a time.
+pub struct PostingList<'a> {
+    data: &'a [u8],
+    /// Offset in the index file of the start of `data`.
+    offset: u64,
+    /// Document id of the last posting decoded.
+    doc_id: Option<u32>,
+}
+
+impl PostingList<'_> {
+    /// Decode the posting list `data`, found at `offset` in an index file.
+    /// (`offset` is only used to report where damaged data is.)
+    pub fn new(data: &[u8], offset: u64) -> PostingList<'_> {
+        PostingList {
+            data,
+            offset,
+            doc_id: None,
+        }
+    }
+
+    fn read_posting(&mut self) -> Option<Posting> {
+        let data = &mut self.data;
+        let delta = read_varint(data)?;
+        let doc_id = match self.doc_id {
+            None => delta,
+            Some(_) if delta == 0 => return None,
+            Some(prev) => prev.checked_add(delta)?,
+        };
+        self.doc_id = Some(doc_id);
+
+        let count = read_varint(data)? as usize;
+        // Every occurrence takes at least 3 bytes.
+        if count > data.len() / 3 {
+            return None;
+        }
+        let mut positions = Vec::with_capacity(count);
+        let (mut position, mut start) = (0u32, 0u32);
+        for _ in 0..count {
+            position = position.checked_add(read_varint(data)?)?;
+            start = start.checked_add(read_varint(data)?)?;
+            let end = start.checked_add(read_varint(data)?)?;
+            positions.push(TokenPos {
+                start_pos: start,
+                end_pos: end,
+                position,
+            });
+        }
+        Some(Posting { doc_id, positions })
+    }
+}
+
+impl Iterator for PostingList<'_> {
+    type Item = Result<Posting>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let len = self.data.len();
+        match self.read_posting() {
+            Some(posting) => {
+                self.offset += (len - self.data.len()) as u64;
+                Some(Ok(posting))
+            }
+            None => {
+                // Nothing after damaged data can be decoded.
+                self.data = &[];
+                Some(Err(corrupt_postings(self.offset)))
+            }
+        }
+    }
+}
+
+/// The error for a posting list that can't be decoded. `offset` is where the
+/// damaged posting starts in the index file.
+fn corrupt_postings(offset: u64) -> IndexError {
+    IndexError::CorruptIndex {
+        what: "posting list cannot be decoded".to_string(),
+        offset,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn posting(doc_id: u32, positions: &[(u32, u32, u32)]) -> Posting {
+        Posting {
+            doc_id,
+            positions: positions
+                .iter()
+                .map(|&(position, start_pos, end_pos)| TokenPos {
+                    start_pos,
+                    end_pos,
+                    position,
+                })
+                .collect(),
+        }
+    }
+
+    fn encode(postings: &[Posting]) -> Vec<u8> {
+        let mut buf = vec![];
+        let mut prev_doc = None;
+        for p in postings {
+            encode_posting(&mut buf, prev_doc, p).unwrap();
+            prev_doc = Some(p.doc_id);
+        }
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Result<Vec<Posting>> {
+        PostingList::new(data, 0).collect()
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, 1 << 28, u32::MAX] {
+            let mut buf = vec![];
+            write_varint(&mut buf, value);
+            buf.push(0xaa);
+            let mut data = &buf[..];
+            assert_eq!(read_varint(&mut data), Some(value));
+            assert_eq!(data, [0xaa]);
+        }
+    }
+
+    #[test]
+    fn truncated_varints_are_rejected() {
+        let mut buf = vec![];
+        write_varint(&mut buf, u32::MAX);
+        for len in 0..buf.len() {
+            assert_eq!(read_varint(&mut &buf[..len]), None, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn overlong_varints_are_rejected() {
+        // Six bytes.
+        assert_eq!(
+            read_varint(&mut &[0x80, 0x80, 0x80, 0x80, 0x80, 0x00][..]),
+            None
+        );
+        // Five bytes, but more than 32 bits.
+        assert_eq!(read_varint(&mut &[0xff, 0xff, 0xff, 0xff, 0x1f][..]), None);
+        assert_eq!(read_varint(&mut &[0x80, 0x80, 0x80, 0x80, 0x10][..]), None);
+        assert_eq!(
+            read_varint(&mut &[0xff, 0xff, 0xff, 0xff, 0x0f][..]),
+            Some(u32::MAX)
+        );
+    }
+
+    #[test]
+    fn postings_round_trip() {
+        let postings = [
+            posting(3, &[(0, 0, 4), (7, 40, 45), (7, 40, 47)]),
+            posting(4, &[]),
+            posting(300, &[(2, 10, 10), (u32::MAX, u32::MAX - 1, u32::MAX)]),
+        ];
+        let data = encode(&postings);
+        assert_eq!(decode(&data).unwrap(), postings);
+    }
+
+    #[test]
+    fn positions_must_be_in_order() {
+        for positions in [
+            &[(1, 5, 8), (0, 10, 12)][..],
+            &[(0, 5, 8), (1, 4, 6)][..],
+            &[(0, 5, 4)][..],
+        ] {
+            let mut buf = vec![1, 2, 3];
+            assert!(matches!(
+                encode_posting(&mut buf, None, &posting(1, positions)),
+                Err(IndexError::UnsortedPositions { doc_id: 1 })
+            ));
+            assert_eq!(buf, [1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn postings_must_be_in_document_order() {
+        let mut buf = vec![];
+        encode_posting(&mut buf, None, &posting(5, &[])).unwrap();
+        for doc_id in [4, 5] {
+            assert!(matches!(
+                encode_posting(&mut buf, Some(5), &posting(doc_id, &[])),
+                Err(IndexError::UnsortedPostings { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn truncated_posting_list_is_corrupt() {
+        let postings = [posting(1, &[(0, 0, 3)]), posting(200, &[(1, 4, 9)])];
+        let data = encode(&postings);
+        // Cut short anywhere but between postings.
+        let boundary = encode(&postings[..1]).len();
+        for len in (1..data.len()).filter(|&len| len != boundary) {
+            let err = decode(&data[..len]).expect_err("truncated list decoded");
+            assert!(matches!(err, IndexError::CorruptIndex { .. }), "{}", err);
+        }
+    }
+
+    #[test]
+    fn rebased_lists_concatenate() {
+        let first = [posting(1, &[(0, 0, 3)]), posting(5, &[(2, 8, 12)])];
+        let second = [posting(200, &[(1, 4, 9)]), posting(201, &[])];
+
+        let mut data = encode(&first);
+        let encoded = encode(&second);
+        let (head, rest) = rebase_posting_list(&encoded, 0, Some(5)).unwrap();
+        data.extend_from_slice(&head);
+        data.extend_from_slice(rest);
+        assert_eq!(decode(&data).unwrap(), [&first[..], &second[..]].concat());
+
+        // Rebasing onto nothing leaves the list as it was.
+        let (head, rest) = rebase_posting_list(&encoded, 0, None).unwrap();
+        assert_eq!([head, rest.to_vec()].concat(), encoded);
+
+        assert!(matches!(
+            rebase_posting_list(&encoded, 0, Some(200)),
+            Err(IndexError::UnsortedPostings { .. })
+        ));
+        assert!(matches!(
+            rebase_posting_list(&[0x80], 7, Some(5)),
+            Err(IndexError::CorruptIndex { offset: 7, .. })
+        ));
+    }
+}
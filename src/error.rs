@@ -0,0 +1,116 @@
+//! Errors of the library.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+/// An error while creating, merging, reading or searching an index.
+#[derive(Debug, Error)]
+pub enum IndexError {
+    /// An I/O error, from the filesystem or from a read past the end of a
+    /// file.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// An I/O error on a file the index is built from or points to.
+    #[error("{}: {source}", path.display())]
+    FileIo { path: PathBuf, source: io::Error },
+
+    /// The file does not start with the magic bytes of an index file.
+    #[error("not an index file")]
+    NotAnIndexFile,
+
+    /// The file was written in a format version this build can't read.
+    #[error("unsupported index file version {found} (this build reads version {supported})")]
+    UnsupportedVersion { found: u32, supported: u32 },
+
+    /// The file uses optional features this build doesn't know about.
+    #[error("index file uses unsupported features (flags {flags:#x})")]
+    UnsupportedFeatures { flags: u32 },
+
+    /// Some data of the file does not match its checksum, does not decode, or
+    /// contradicts other parts of the file. `what` describes the data and
+    /// `offset` is where it starts in the file.
+    #[error("index file is corrupt: {what} at offset {offset}")]
+    CorruptIndex { what: String, offset: u64 },
+
+    /// The file ends before some data it points to.
+    #[error("index file is truncated: data at offset {offset} is past the end of the file")]
+    Truncated { offset: u64 },
+
+    /// A term in the table of entries, at `offset` in the file, is not valid
+    /// UTF-8.
+    #[error("term at offset {offset} is not valid UTF-8")]
+    InvalidUtf8Term { offset: u64 },
+
+    /// A posting refers to a document that isn't in the table of documents.
+    #[error("document {id} is missing from the index")]
+    DocumentMissing { id: u32 },
+
+    /// The stored text of a document does not decompress. Its checksum
+    /// matched, so it was damaged before it was written.
+    #[error("stored text of document {id} is corrupt")]
+    CorruptStoredText { id: u32 },
+
+    /// A query could not be parsed.
+    #[error("invalid query: {0}")]
+    QueryParse(String),
+
+    /// No analyzer has the given name.
+    #[error("unknown analyzer {0:?}")]
+    UnknownAnalyzer(String),
+
+    /// An index built with one analyzer was merged with, or searched with,
+    /// another.
+    #[error("index was built with analyzer {index:?}, not {other:?}")]
+    AnalyzerMismatch { index: String, other: String },
+
+    /// Postings were written out of order of document id.
+    #[error("postings are not sorted by document id (document {doc_id} after {prev_doc})")]
+    UnsortedPostings { doc_id: u32, prev_doc: u32 },
+
+    /// The positions of a posting were out of order, or one of them ended
+    /// before it started.
+    #[error("positions in the posting for document {doc_id} are not sorted")]
+    UnsortedPositions { doc_id: u32 },
+
+    /// There is nothing to write an index of.
+    #[error("no documents were parsed or none contained any words")]
+    NoDocuments,
+
+    /// An `IndexFileReader` was asked to read past the end of the table of
+    /// entries, or to read the table of documents before the end of the table
+    /// of entries.
+    #[error("index file read out of order: {0}")]
+    ReadOutOfOrder(&'static str),
+
+    /// A background merge thread exited without reporting its result.
+    #[error("a merge thread exited unexpectedly")]
+    MergeThread,
+}
+
+/// Shorthand for results whose error is an `IndexError`.
+pub type Result<T> = std::result::Result<T, IndexError>;
+
+impl IndexError {
+    /// A function that wraps an I/O error on the file at `path` into a
+    /// `FileIo` error, for `map_err`.
+    pub fn file_io(path: &Path) -> impl FnOnce(io::Error) -> IndexError + '_ {
+        move |source| IndexError::FileIo {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// The error for data starting at `offset` in the file whose checksum does
+    /// not match the one stored for it. `what` describes the damaged data.
+    pub(crate) fn checksum_mismatch(what: impl Into<String>, offset: u64) -> IndexError {
+        IndexError::CorruptIndex {
+            what: format!("checksum mismatch in {}", what.into()),
+            offset,
+        }
+    }
+}
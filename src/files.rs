@@ -0,0 +1,43 @@
+//! Finding the files to index.
+//!
+//! `create` indexes, and `status` checks, the files named on the command
+//! line and the files in the directories named there. Both list them the
+//! same way, so that `status` can tell which files `create` would index that
+//! an index doesn't have.
+
+use std::path::PathBuf;
+
+use crate::error::{IndexError, Result};
+
+/// Given some paths, generate the complete list of text files to index. We check
+/// on disk whether the path is the name of a file or a directory; for
+/// directories, all .txt files immediately under the directory are indexed,
+/// sorted by name so that documents get the same ids on every run.
+/// Relative paths are fine.
+///
+/// It's an error if any of the `args` is not a valid path to an existing file
+/// or directory. Errors name the path they are about.
+pub fn expand_filename_arguments(args: &[String]) -> Result<Vec<PathBuf>> {
+    let mut filenames = vec![];
+    for arg in args {
+        let path = PathBuf::from(arg);
+        let metadata = path.metadata().map_err(IndexError::file_io(&path))?;
+        if metadata.is_dir() {
+            let mut entries = vec![];
+            for entry in path.read_dir().map_err(IndexError::file_io(&path))? {
+                let entry = entry.map_err(IndexError::file_io(&path))?;
+                let file_type = entry
+                    .file_type()
+                    .map_err(IndexError::file_io(&entry.path()))?;
+                if file_type.is_file() {
+                    entries.push(entry.path());
+                }
+            }
+            entries.sort();
+            filenames.extend(entries);
+        } else {
+            filenames.push(path);
+        }
+    }
+    Ok(filenames)
+}
@@ -0,0 +1,92 @@
+//! File fingerprints.
+//!
+//! Every document in an index records the size, modification time and a hash
+//! of the content of its file, as they were when it was indexed. Comparing
+//! them with the file on disk tells whether the index is out of date for
+//! that file. The size and modification time can be checked without reading
+//! the file; only when they differ is the file read and hashed, so touching
+//! a file without changing it doesn't count as a change.
+
+use std::{
+    fs::{self, Metadata},
+    io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How the file a document was indexed from compares with its fingerprint;
+/// see `Fingerprint::disk_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskState {
+    /// The file has the same content as when it was indexed.
+    Unchanged,
+    /// The file has different content.
+    Modified,
+    /// There is no file at the path any more.
+    Missing,
+}
+
+/// The size, modification time and content hash of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Fingerprint {
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Modification time of the file, in nanoseconds since the Unix epoch (0
+    /// for earlier times).
+    pub mtime: u64,
+    /// `content_hash` of the content of the file.
+    pub hash: u64,
+}
+
+impl Fingerprint {
+    /// The fingerprint of a file with the given content and modification
+    /// time.
+    pub fn new(content: &[u8], modified: SystemTime) -> Fingerprint {
+        Fingerprint {
+            size: content.len() as u64,
+            mtime: nanos_since_epoch(modified),
+            hash: content_hash(content),
+        }
+    }
+
+    /// Compare the file at `path` with this fingerprint.
+    pub fn disk_state(&self, path: &Path) -> io::Result<DiskState> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(DiskState::Missing),
+            Err(err) => return Err(err),
+        };
+        if metadata.len() != self.size {
+            return Ok(DiskState::Modified);
+        }
+        if self.same_mtime(&metadata) {
+            return Ok(DiskState::Unchanged);
+        }
+        if content_hash(&fs::read(path)?) == self.hash {
+            Ok(DiskState::Unchanged)
+        } else {
+            Ok(DiskState::Modified)
+        }
+    }
+
+    fn same_mtime(&self, metadata: &Metadata) -> bool {
+        metadata
+            .modified()
+            .is_ok_and(|modified| nanos_since_epoch(modified) == self.mtime)
+    }
+}
+
+/// The 64-bit FNV-1a hash of `data`. It is not cryptographic, but it is
+/// fast, and any edit to a file changes it with overwhelming probability.
+pub fn content_hash(data: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    data.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(PRIME)
+    })
+}
+
+fn nanos_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64)
+}
@@ -0,0 +1,365 @@
+//! Highlighting matches in document text.
+//!
+//! A search returns where the terms of the query appear in each document, as
+//! byte offsets (see `SearchHit::matches`). A `Highlighter` turns those
+//! offsets into spans of some text, dropping any that don't fit it, and hands
+//! the text and the spans to a `HighlightFormat`, which decides what they
+//! look like: ANSI colors for a terminal, `<mark>` for a web page, bold for
+//! Markdown, or just the list of spans as JSON for a program that does its
+//! own rendering. All of them work from the same match data.
+
+use std::{fmt::Write, str::FromStr};
+
+use crate::index::TokenPos;
+
+/// A range of bytes of the highlighted text, `start..end`. Both ends are on
+/// character boundaries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Renders text with some spans of it highlighted.
+pub trait HighlightFormat: Send + Sync {
+    /// Render `text`. `spans` are sorted, don't overlap, and are valid
+    /// ranges of `text`.
+    fn format(&self, text: &str, spans: &[Span]) -> String;
+
+    /// Render `text` with nothing highlighted, as it must appear next to
+    /// what `format` renders.
+    fn escape(&self, text: &str) -> String;
+}
+
+/// Highlights matches in text with a `HighlightFormat`.
+pub struct Highlighter {
+    format: Box<dyn HighlightFormat>,
+}
+
+impl Highlighter {
+    pub fn new<F: HighlightFormat + 'static>(format: F) -> Highlighter {
+        Highlighter {
+            format: Box::new(format),
+        }
+    }
+
+    /// Render `text` with `matches` highlighted. `text` starts at byte
+    /// `offset` of the document the positions of `matches` refer to, as the
+    /// text of a `Snippet` does.
+    pub fn highlight(&self, text: &str, offset: usize, matches: &[TokenPos]) -> String {
+        self.format.format(text, &spans(text, offset, matches))
+    }
+
+    /// Render `text` with nothing highlighted, escaped as the format needs
+    /// it: text that goes alongside highlighted text, like a file name.
+    pub fn escape(&self, text: &str) -> String {
+        self.format.escape(text)
+    }
+}
+
+impl Default for Highlighter {
+    fn default() -> Self {
+        Highlighter::new(Ansi::default())
+    }
+}
+
+/// The spans of `text` covered by `matches`, where `text` starts at byte
+/// `offset` of the document. Matches that fall outside `text`, overlap an
+/// earlier match or don't start and end on character boundaries (for example
+/// because the file changed after it was indexed) are left out.
+pub fn spans(text: &str, offset: usize, matches: &[TokenPos]) -> Vec<Span> {
+    let mut matches = matches.to_vec();
+    matches.sort_by_key(|pos| pos.start_pos);
+
+    let mut spans: Vec<Span> = vec![];
+    for pos in matches {
+        let Some(start) = (pos.start_pos as usize).checked_sub(offset) else {
+            continue;
+        };
+        let end = (pos.end_pos as usize).saturating_sub(offset);
+        let after_previous = spans.last().is_none_or(|span| span.end <= start);
+        if start < end
+            && end <= text.len()
+            && after_previous
+            && text.is_char_boundary(start)
+            && text.is_char_boundary(end)
+        {
+            spans.push(Span { start, end });
+        }
+    }
+    spans
+}
+
+/// Wrap every span of `text` in `before` and `after`, passing the rest of the
+/// text, and the text of the spans, through `escape`.
+fn wrap_spans(
+    text: &str,
+    spans: &[Span],
+    before: &str,
+    after: &str,
+    escape: impl Fn(&mut String, &str),
+) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut end = 0;
+    for span in spans {
+        escape(&mut out, &text[end..span.start]);
+        out.push_str(before);
+        escape(&mut out, &text[span.start..span.end]);
+        out.push_str(after);
+        end = span.end;
+    }
+    escape(&mut out, &text[end..]);
+    out
+}
+
+/// A terminal color for `Ansi`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Color {
+    Black,
+    #[default]
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+impl Color {
+    /// The SGR parameter that sets this color as the foreground color.
+    fn foreground_code(self) -> u8 {
+        30 + self as u8
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Color, String> {
+        match s {
+            "black" => Ok(Color::Black),
+            "red" => Ok(Color::Red),
+            "green" => Ok(Color::Green),
+            "yellow" => Ok(Color::Yellow),
+            "blue" => Ok(Color::Blue),
+            "magenta" => Ok(Color::Magenta),
+            "cyan" => Ok(Color::Cyan),
+            "white" => Ok(Color::White),
+            _ => Err(format!(
+                "unknown color {:?} (expected black, red, green, yellow, blue, magenta, cyan or white)",
+                s
+            )),
+        }
+    }
+}
+
+/// Highlights matches with ANSI escape codes, for terminals.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ansi {
+    pub color: Color,
+    pub bold: bool,
+}
+
+impl HighlightFormat for Ansi {
+    fn format(&self, text: &str, spans: &[Span]) -> String {
+        let before = if self.bold {
+            format!("\x1b[1;{}m", self.color.foreground_code())
+        } else {
+            format!("\x1b[{}m", self.color.foreground_code())
+        };
+        wrap_spans(text, spans, &before, "\x1b[0m", |out, s| out.push_str(s))
+    }
+
+    fn escape(&self, text: &str) -> String {
+        text.to_string()
+    }
+}
+
+/// Highlights matches with `<mark>` and escapes the text for HTML.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Html;
+
+impl HighlightFormat for Html {
+    fn format(&self, text: &str, spans: &[Span]) -> String {
+        wrap_spans(text, spans, "<mark>", "</mark>", escape_html)
+    }
+
+    fn escape(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        escape_html(&mut out, text);
+        out
+    }
+}
+
+fn escape_html(out: &mut String, s: &str) {
+    for ch in s.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(ch),
+        }
+    }
+}
+
+/// Highlights matches in bold and escapes the text for Markdown.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Markdown;
+
+impl HighlightFormat for Markdown {
+    fn format(&self, text: &str, spans: &[Span]) -> String {
+        wrap_spans(text, spans, "**", "**", escape_markdown)
+    }
+
+    fn escape(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        escape_markdown(&mut out, text);
+        out
+    }
+}
+
+fn escape_markdown(out: &mut String, s: &str) {
+    for ch in s.chars() {
+        if matches!(
+            ch,
+            '\\' | '`' | '*' | '_' | '{' | '}' | '[' | ']' | '<' | '>' | '(' | ')' | '#' | '|'
+        ) {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+}
+
+/// Renders only the spans, as a JSON array of objects with the `start` and
+/// (exclusive) `end` byte offsets and the `text` of each span.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonSpans;
+
+impl HighlightFormat for JsonSpans {
+    fn format(&self, text: &str, spans: &[Span]) -> String {
+        let mut out = String::from("[");
+        for (i, span) in spans.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"start\":{},\"end\":{},\"text\":{}}}",
+                span.start,
+                span.end,
+                json_string(&text[span.start..span.end])
+            );
+        }
+        out.push(']');
+        out
+    }
+
+    /// The spans alone don't include any text, so text next to them is a
+    /// JSON string.
+    fn escape(&self, text: &str) -> String {
+        json_string(text)
+    }
+}
+
+/// `s` as a JSON string literal, quotes included.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", ch as u32);
+            }
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(start_pos: u32, end_pos: u32) -> TokenPos {
+        TokenPos {
+            start_pos,
+            end_pos,
+            position: 0,
+        }
+    }
+
+    #[test]
+    fn html_escapes_text_and_matches() {
+        let text = r#"if a<b && c>"d" then 'e'"#;
+        let html = Highlighter::new(Html);
+        assert_eq!(
+            html.highlight(text, 0, &[pos(3, 4), pos(22, 23)]),
+            "if <mark>a</mark>&lt;b &amp;&amp; c&gt;&quot;d&quot; then &#39;<mark>e</mark>&#39;"
+        );
+        assert_eq!(html.escape("<a & b>"), "&lt;a &amp; b&gt;");
+    }
+
+    #[test]
+    fn markdown_escapes_text_and_matches() {
+        let text = "*not* a [link](x) or `code` #1 a_b|c";
+        let markdown = Highlighter::new(Markdown);
+        assert_eq!(
+            markdown.highlight(text, 0, &[pos(0, 5), pos(31, 34)]),
+            r"**\*not\*** a \[link\]\(x\) or \`code\` \#1 **a\_b**\|c"
+        );
+        assert_eq!(markdown.escape(r"C:\my_dir"), r"C:\\my\_dir");
+    }
+
+    #[test]
+    fn json_spans_are_offsets_into_the_text() {
+        let text = r#"café, straße: "naïve""#;
+        let json = Highlighter::new(JsonSpans);
+        // The text starts at byte 10 of the document.
+        let matches = [pos(10, 15), pos(17, 24), pos(26, 34)];
+        assert_eq!(
+            json.highlight(text, 10, &matches),
+            r#"[{"start":0,"end":5,"text":"café"},{"start":7,"end":14,"text":"straße"},{"start":16,"end":24,"text":"\"naïve\""}]"#
+        );
+        assert_eq!(json.highlight(text, 0, &[]), "[]");
+        assert_eq!(json.escape(r#"a "b".txt"#), r#""a \"b\".txt""#);
+    }
+
+    #[test]
+    fn ansi_colors_matches() {
+        let text = "one two three";
+        let matches = [pos(4, 7)];
+        assert_eq!(
+            Highlighter::default().highlight(text, 0, &matches),
+            "one \x1b[31mtwo\x1b[0m three"
+        );
+        let ansi = Highlighter::new(Ansi {
+            color: Color::Cyan,
+            bold: true,
+        });
+        assert_eq!(
+            ansi.highlight(text, 0, &matches),
+            "one \x1b[1;36mtwo\x1b[0m three"
+        );
+        assert_eq!(ansi.escape("<a>"), "<a>");
+    }
+
+    #[test]
+    fn spans_outside_the_text_are_dropped() {
+        let text = "héllo world";
+        // Outside, overlapping an earlier match, inside a character.
+        let matches = [pos(0, 6), pos(20, 25), pos(3, 8), pos(2, 4), pos(7, 12)];
+        assert_eq!(
+            spans(text, 0, &matches),
+            [Span { start: 0, end: 6 }, Span { start: 7, end: 12 }]
+        );
+    }
+}
@@ -5,22 +5,38 @@
 //! memory.
 
 use std::{
-    collections::HashMap,
-    ffi::OsString,
-    io::{self, Cursor, Read, Seek},
-    os::unix::ffi::OsStringExt,
+    collections::{hash_map, HashMap},
+    io::{self, Read, Seek},
+    mem::size_of,
     path::{Path, PathBuf},
-    vec,
+    time::SystemTime,
 };
 
-use byteorder::*;
-
-use crate::read::IndexFileReader;
+use tracing::debug;
+
+use crate::{
+    analysis::Analyzer,
+    checksum::checksum,
+    codec::PostingList,
+    error::{IndexError, Result},
+    fingerprint::Fingerprint,
+    query::{DocSet, Query},
+    read::IndexFileReader,
+    results::{match_positions, SearchHit, SearchResults},
+    score::{Bm25, CorpusStats, ScoredDoc},
+    store,
+};
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct TokenPos {
+    /// Byte offset of the first byte of the token in the document.
     pub start_pos: u32,
+    /// Byte offset just past the last byte of the token in the document. Both
+    /// offsets are on character boundaries of the original text, whatever
+    /// the analyzer did to the token.
     pub end_pos: u32,
+    /// Ordinal of the token in its document (see `Token::position`).
+    pub position: u32,
 }
 
 /// An in-memory index.
@@ -35,97 +51,196 @@ pub struct InMemoryIndex {
     /// The total number of words in the indexed documents.
     pub word_count: usize,
 
-    /// For every term that appears in the index, the list of all search hits
+    /// Approximate number of bytes of heap memory used by this index: term
+    /// strings, postings and document paths, plus some bookkeeping for each.
+    pub heap_bytes: usize,
+
+    /// For every term that appears in the index, the list of all postings
     /// for that term (i.e. which documents contain that term, and where).
     ///
     /// It's possible for an index to be "sorted by document id", which means
-    /// that for every `Vec<Hit>` in this map, the `Hit` elements all have
-    /// distinct document ids (the first u32) and the `Hit`s are arranged by
-    /// document id in increasing order. This is handy for some algorithms you
-    /// might want to run on the index, so we preserve this property wherever
-    /// possible.
-    pub terms: HashMap<String, Vec<Hit>>,
+    /// that for every `Vec<Posting>` in this map, the `Posting` elements all
+    /// have distinct document ids and are arranged by document id in
+    /// increasing order. This is handy for some algorithms you might want to
+    /// run on the index, so we preserve this property wherever possible.
+    pub terms: HashMap<String, Vec<Posting>>,
 
     pub docs: HashMap<u32, Document>,
+
+    /// The name of the `Analyzer` that produced the terms in this index.
+    pub analyzer: String,
 }
 
 #[derive(Debug)]
 pub struct Document {
     pub id: u32,
+    /// The path of the file. `create` records it absolute and canonical, so
+    /// that it names the same file from any working directory.
     pub path: PathBuf,
+    /// The number of tokens in the document, used to normalize scores.
+    pub length: u32,
+    /// The size, modification time and content hash of the file when it was
+    /// indexed, to tell whether it changed since.
+    pub fingerprint: Fingerprint,
+    /// The text of the document as it was indexed, compressed, if the index
+    /// stores it. See `stored_text`. Only documents being indexed and those
+    /// loaded by `InMemoryIndex::from_index_file` have it;
+    /// `IndexSearcher::stored_text` reads it from the file instead.
+    pub stored: Option<Vec<u8>>,
 }
 
-/// A `Hit` indicates that a particular document contains some term, how many
-/// times it appears, and at what offsets (that is, the word count, from the
-/// beginning of the document, of each place where the term appears).
+impl Document {
+    /// The text of the document as it was indexed, if the index stores it.
+    pub fn stored_text(&self) -> Result<Option<String>> {
+        let Some(data) = &self.stored else {
+            return Ok(None);
+        };
+        store::decompress(data)
+            .map(Some)
+            .map_err(|_| IndexError::CorruptStoredText { id: self.id })
+    }
+}
+
+/// Assigns document ids: consecutive numbers starting at `FIRST_ID`, in the
+/// order the documents are given.
 ///
-/// The buffer contains all the hit data in binary form, little-endian. The
-/// first u32 of the data is the document id. The remaining [u32] are offsets.
-pub type Hit = Vec<u8>;
+/// Indexes built from the same documents in the same order get the same ids,
+/// however they are built.
+#[derive(Debug)]
+pub struct DocIdAllocator {
+    next: u32,
+}
 
-impl InMemoryIndex {
-    const HITS_SEPERATOR: i32 = -1;
+impl DocIdAllocator {
+    /// The id of the first document.
+    pub const FIRST_ID: u32 = 1;
+
+    pub fn new() -> DocIdAllocator {
+        DocIdAllocator {
+            next: Self::FIRST_ID,
+        }
+    }
+
+    /// The id of the next document.
+    pub fn allocate(&mut self) -> u32 {
+        let id = self.next;
+        self.next += 1;
+        id
+    }
+}
+
+impl Default for DocIdAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    /// Create a new, empty index.
+/// A `Posting` indicates that a particular document contains some term, how
+/// many times it appears, and where: the byte offsets and the token ordinal of
+/// each place where the term appears, in increasing order.
+///
+/// See the `codec` module for how postings are stored in index files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Posting {
+    pub doc_id: u32,
+    pub positions: Vec<TokenPos>,
+}
+
+impl InMemoryIndex {
+    /// Create a new, empty index, for terms produced by the standard analyzer.
     pub fn new() -> InMemoryIndex {
+        InMemoryIndex::with_analyzer(Analyzer::STANDARD)
+    }
+
+    /// Create a new, empty index, for terms produced by the named analyzer.
+    pub fn with_analyzer(analyzer: &str) -> InMemoryIndex {
         InMemoryIndex {
             word_count: 0,
+            heap_bytes: 0,
             terms: HashMap::new(),
             docs: HashMap::new(),
+            analyzer: analyzer.to_string(),
         }
     }
 
-    /// Index a single document.
+    /// Index a single document, breaking its text into terms with `analyzer`.
+    /// `modified` is the modification time of its file, recorded in its
+    /// fingerprint with the size and hash of `text`. If `store_text` is true,
+    /// the text itself is kept too, compressed.
     ///
-    /// The resulting index contains exactly one `Hit` per term.
-    pub fn from_single_document(document_id: u32, path: PathBuf, text: String) -> InMemoryIndex {
-        let mut index = InMemoryIndex::new();
-
-        let text_lowercase = text.to_lowercase();
-        let tokens = tokenize(&text_lowercase);
-        for (token, start_pos, end_pos) in tokens.iter() {
-            let hits = index.terms.entry(token.to_string()).or_insert_with(|| {
-                let mut hits = Vec::with_capacity(4 + 4 + 4);
-                hits.write_i32::<LittleEndian>(Self::HITS_SEPERATOR)
-                    .unwrap();
-                hits.write_u32::<LittleEndian>(document_id).unwrap();
-                vec![hits]
+    /// The resulting index contains exactly one `Posting` per term.
+    pub fn from_single_document(
+        analyzer: &Analyzer,
+        document_id: u32,
+        path: PathBuf,
+        text: String,
+        modified: SystemTime,
+        store_text: bool,
+    ) -> InMemoryIndex {
+        let mut index = InMemoryIndex::with_analyzer(analyzer.name());
+
+        let tokens = analyzer.analyze(&text);
+        let length = tokens.len() as u32;
+        let mut positions: HashMap<String, Vec<TokenPos>> = HashMap::new();
+        for token in tokens {
+            positions.entry(token.text).or_default().push(TokenPos {
+                start_pos: token.start as u32,
+                end_pos: token.end as u32,
+                position: token.position,
             });
-
-            hits[0]
-                .write_u32::<LittleEndian>(*start_pos as u32)
-                .unwrap();
-            hits[0].write_u32::<LittleEndian>(*end_pos as u32).unwrap();
             index.word_count += 1;
         }
+        for (term, positions) in positions {
+            let postings = vec![Posting {
+                doc_id: document_id,
+                positions,
+            }];
+            index.heap_bytes += term_heap_bytes(&term) + postings_heap_bytes(&postings);
+            index.terms.insert(term, postings);
+        }
 
-        println!(
-            "indexed document {}:{:?}, {} bytes, {} words",
-            document_id,
-            &path,
-            &text.len(),
-            index.word_count
+        debug!(
+            doc_id = document_id,
+            path = %path.display(),
+            bytes = text.len(),
+            words = index.word_count,
+            "indexed document"
         );
 
-        let _ = index.docs.insert(
-            document_id,
-            Document {
-                id: document_id,
-                path,
-            },
-        );
+        let doc = Document {
+            id: document_id,
+            path,
+            length,
+            fingerprint: Fingerprint::new(text.as_bytes(), modified),
+            stored: store_text.then(|| store::compress(&text)),
+        };
+        index.heap_bytes += document_heap_bytes(&doc);
+        let _ = index.docs.insert(document_id, doc);
 
         index
     }
 
-    /// Add all search hits from `other` to this index.
+    /// Add all postings from `other` to this index.
     ///
     /// If both `*self` and `other` are sorted by document id, and all document
     /// ids in `other` are greater than every document id in `*self`, then
     /// `*self` remains sorted by document id after merging.
+    ///
+    /// Both indexes must have been built with the same analyzer.
     pub fn merge(&mut self, other: InMemoryIndex) {
-        for (term, hits) in other.terms {
-            self.terms.entry(term).or_default().extend(hits)
+        debug_assert_eq!(self.analyzer, other.analyzer);
+        self.heap_bytes += other.heap_bytes;
+        for (term, postings) in other.terms {
+            match self.terms.entry(term) {
+                hash_map::Entry::Occupied(mut entry) => {
+                    // Only one copy of the term is kept.
+                    self.heap_bytes -= term_heap_bytes(entry.key());
+                    entry.get_mut().extend(postings);
+                }
+                hash_map::Entry::Vacant(entry) => {
+                    entry.insert(postings);
+                }
+            }
         }
         self.word_count += other.word_count;
         self.docs.extend(other.docs);
@@ -136,184 +251,181 @@ impl InMemoryIndex {
         self.word_count == 0
     }
 
-    /// True if this index is large enough that we should dump it to disk rather
-    /// than keep adding more data to it.
-    pub fn is_large(&self) -> bool {
-        // This depends on how much memory your computer has, of course.
-        const REASONABLE_SIZE: usize = 100_000_000;
-        self.word_count > REASONABLE_SIZE
+    /// True if this index uses at least `memory_budget` bytes of heap memory,
+    /// so that we should dump it to disk rather than keep adding more data to
+    /// it.
+    pub fn exceeds(&self, memory_budget: usize) -> bool {
+        self.heap_bytes >= memory_budget
     }
 
     // Load an InMemoryIndex from an index file.
-    pub fn from_index_file<P: AsRef<Path>>(filename: P) -> io::Result<InMemoryIndex> {
-        let mut index = InMemoryIndex::new();
+    pub fn from_index_file<P: AsRef<Path>>(filename: P) -> Result<InMemoryIndex> {
         let mut reader = IndexFileReader::open_and_delete(filename, false)?;
-
-        while let Some(entry) = reader.iter_next_entry() {
-            if entry.term.is_empty() && entry.df == 0 {
-                // documents
-                reader.terms_docs.seek(io::SeekFrom::Start(entry.offset))?;
-                let doc_id = reader.terms_docs.read_u32::<LittleEndian>()?;
-                let path_len = reader.terms_docs.read_u64::<LittleEndian>()?;
-                let mut path = vec![0u8; path_len as usize];
-                reader.terms_docs.read_exact(&mut path)?;
-                index.docs.insert(
-                    doc_id,
-                    Document {
-                        id: doc_id,
-                        path: vec_to_pathbuf(path),
-                    },
-                );
-            } else {
-                // entrys
-                let mut hits = vec![];
-                reader.terms_docs.seek(io::SeekFrom::Start(entry.offset))?;
-                let mut data = vec![0u8; entry.nbytes as usize];
-                reader.terms_docs.read_exact(&mut data)?;
-                let mut cursor = Cursor::new(data);
-
-                let mut i = entry.df;
-                let mut has_hit = false;
-                let mut quit = false;
-
-                while i > 0 && !quit {
-                    let mut hit = Vec::with_capacity(4 + 4 + 4); // cannot use vec![0;12]
-                    loop {
-                        if let Ok(item) = cursor.read_i32::<LittleEndian>() {
-                            // the start of next hit
-                            if item == Self::HITS_SEPERATOR && has_hit {
-                                hits.push(hit);
-                                i -= 1;
-                                index.word_count -= 2;
-                                hit = Vec::with_capacity(4 + 4 + 4);
-                            }
-                            has_hit = true;
-                            hit.write_u32::<LittleEndian>(item as u32).unwrap();
-                            index.word_count += 1;
-                        } else {
-                            quit = true;
-                            if !hit.is_empty() {
-                                hits.push(hit);
-                                index.word_count -= 2;
-                            }
-                            break;
-                        }
-                    }
-                }
-                index.terms.insert(entry.term, hits);
+        let mut index = InMemoryIndex::with_analyzer(reader.analyzer());
+
+        while let Some(entry) = reader.iter_next_entry()? {
+            reader.terms_docs.seek(io::SeekFrom::Start(entry.offset))?;
+            let mut data = vec![0u8; entry.nbytes as usize];
+            reader.terms_docs.read_exact(&mut data)?;
+            if checksum(&data) != entry.crc {
+                return Err(IndexError::checksum_mismatch(
+                    format!("hits of term {:?}", entry.term),
+                    entry.offset,
+                ));
             }
+            let postings: Vec<Posting> =
+                PostingList::new(&data, entry.offset).collect::<Result<_>>()?;
+            index.heap_bytes += term_heap_bytes(&entry.term) + postings_heap_bytes(&postings);
+            index.terms.insert(entry.term, postings);
         }
-        index.word_count /= 2;
+        for doc in reader.read_documents()? {
+            index.heap_bytes += document_heap_bytes(&doc);
+            index.docs.insert(doc.id, doc);
+        }
+        index.word_count = reader.word_count() as usize;
         Ok(index)
     }
 
-    // Search all documents that contain the term
-    // and highlights where the term appears.
-    pub fn search(&self, term: &str) -> io::Result<()> {
-        let m: Option<&Vec<Vec<u8>>> = self.terms.get(term);
-        if m.is_none() {
-            println!("can not found {} in all documents", term);
-            return Ok(());
+    /// Find the documents that match `query` and return the `top_k` best
+    /// ones according to `ranking`, with where the terms of the query appear
+    /// in each.
+    ///
+    /// The query must have been parsed with the analyzer this index was built
+    /// with (see `analyzer`).
+    pub fn search(&self, query: &Query, ranking: &Bm25, top_k: usize) -> Result<SearchResults> {
+        let terms = query.positive_terms();
+        let mut hits = vec![];
+        for ScoredDoc { document_id, score } in ranking.rank(query, self, top_k) {
+            let doc = self
+                .docs
+                .get(&document_id)
+                .ok_or(IndexError::DocumentMissing { id: document_id })?;
+            hits.push(SearchHit {
+                doc_id: document_id,
+                path: doc.path.clone(),
+                score,
+                matches: match_positions(
+                    terms
+                        .iter()
+                        .filter_map(|term| self.posting(term, document_id)),
+                ),
+            });
         }
-        let hits = m.unwrap();
-        for hit in hits {
-            let mut cursor = Cursor::new(hit);
-            let _ = cursor.read_i32::<LittleEndian>().unwrap();
-
-            let document_id = cursor.read_u32::<LittleEndian>().unwrap();
-            let doc = self.docs.get(&document_id);
-            if doc.is_none() {
-                println!("cannot found document {}", document_id);
-                continue;
-            }
-            let doc = doc.unwrap();
-            let mut poss = Vec::with_capacity(hits.len() / 4);
-            let mut pos = TokenPos::default();
-            let mut has_pos = false;
-            while let Ok(p) = cursor.read_u32::<LittleEndian>() {
-                if !has_pos {
-                    pos.start_pos = p;
-                    has_pos = true;
-                } else {
-                    pos.end_pos = p;
-                    poss.push(pos);
-                    pos = TokenPos::default();
-                    has_pos = false;
-                }
-            }
+        Ok(SearchResults { hits })
+    }
 
-            let result = highlight_file(doc.path.clone(), &mut poss)?;
-            println!("\n{:?}: \n{}", doc.path, result);
-        }
-        Ok(())
+    /// The `Posting` for `term` in the given document, if the document
+    /// contains the term. Relies on the index being sorted by document id.
+    fn posting(&self, term: &str, document_id: u32) -> Option<&Posting> {
+        find_posting(self.terms.get(term)?, document_id)
     }
 }
 
-impl Default for InMemoryIndex {
-    fn default() -> Self {
-        Self::new()
+impl CorpusStats for InMemoryIndex {
+    fn doc_count(&self) -> u32 {
+        self.docs.len() as u32
+    }
+
+    fn total_doc_length(&self) -> u64 {
+        self.docs.values().map(|doc| doc.length as u64).sum()
+    }
+
+    fn doc_length(&self, document_id: u32) -> u32 {
+        self.docs.get(&document_id).map_or(0, |doc| doc.length)
+    }
+
+    fn doc_freq(&self, term: &str) -> u32 {
+        self.terms
+            .get(term)
+            .map_or(0, |postings| postings.len() as u32)
     }
 }
 
-/// Break text into words
-fn tokenize(text: &str) -> Vec<(&str, usize, usize)> {
-    let mut res = Vec::new();
-    let mut token_start = None;
-    for (idx, ch) in text.char_indices() {
-        match (ch.is_alphanumeric(), token_start) {
-            // start of a word
-            (true, None) => token_start = Some(idx),
-            // end of a word
-            (false, Some(start)) => {
-                res.push((&text[start..idx], start, idx - 1));
-                token_start = None
-            }
-            _ => {}
+impl DocSet for InMemoryIndex {
+    fn term_docs(&self, term: &str) -> Vec<u32> {
+        match self.terms.get(term) {
+            Some(postings) => postings.iter().map(|posting| posting.doc_id).collect(),
+            None => vec![],
         }
     }
 
-    // the last one.
-    if let Some(start) = token_start {
-        res.push((&text[start..], start, text.len() - 1))
+    fn term_positions(&self, term: &str, document_id: u32) -> Vec<u32> {
+        match self.posting(term, document_id) {
+            Some(posting) => posting.positions.iter().map(|pos| pos.position).collect(),
+            None => vec![],
+        }
+    }
+
+    fn all_docs(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.docs.keys().copied().collect();
+        ids.sort();
+        ids
     }
-    res
 }
 
-fn highlight_file(path: PathBuf, poss: &mut Vec<TokenPos>) -> io::Result<String> {
-    let mut origin_text = std::fs::read_to_string(path)?;
-    let mut extra_chars = 0;
+impl Default for InMemoryIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    // Make sure the poss is sorted by `start_pos` to prevent misalignment.
-    poss.sort_by_key(|pos| pos.start_pos);
+// Every entry of a `HashMap` costs about one word of bookkeeping besides its
+// key and value.
+const HASH_ENTRY_OVERHEAD: usize = size_of::<usize>();
 
-    for pos in poss.iter() {
-        // Adjust the position index to add the number of additional characters that have been inserted.
-        let start_pos_adjusted = (pos.start_pos as usize) + extra_chars;
-        let end_pos_adjusted = (pos.end_pos as usize) + extra_chars;
+/// Approximate heap memory used by a term of `InMemoryIndex::terms`, not
+/// counting its postings.
+fn term_heap_bytes(term: &str) -> usize {
+    HASH_ENTRY_OVERHEAD + size_of::<String>() + size_of::<Vec<Posting>>() + term.len()
+}
 
-        origin_text = highlight_text(&origin_text, start_pos_adjusted, end_pos_adjusted);
+/// Approximate heap memory used by the postings of a term.
+fn postings_heap_bytes(postings: &[Posting]) -> usize {
+    postings
+        .iter()
+        .map(|posting| size_of::<Posting>() + posting.positions.len() * size_of::<TokenPos>())
+        .sum()
+}
 
-        extra_chars += 9; // the total length of `\x1b[31m` and `\x1b[0m`
-    }
+/// Approximate heap memory used by a document of `InMemoryIndex::docs`.
+fn document_heap_bytes(doc: &Document) -> usize {
+    HASH_ENTRY_OVERHEAD
+        + size_of::<u32>()
+        + size_of::<Document>()
+        + doc.path.as_os_str().len()
+        + doc.stored.as_ref().map_or(0, Vec::len)
+}
 
-    Ok(origin_text)
+/// The posting for the given document in `postings`, which must be sorted by
+/// document id.
+pub(crate) fn find_posting(postings: &[Posting], document_id: u32) -> Option<&Posting> {
+    postings
+        .binary_search_by_key(&document_id, |posting| posting.doc_id)
+        .ok()
+        .map(|i| &postings[i])
 }
 
-fn highlight_text(text: &str, start_pos: usize, end_pos: usize) -> String {
-    if start_pos > text.len() || end_pos >= text.len() || start_pos > end_pos {
-        return text.to_string(); // Returning the original text if the positions are invalid
+#[cfg(test)]
+impl InMemoryIndex {
+    /// An index of `texts` built with the standard analyzer, numbering
+    /// documents from 1 in order.
+    pub(crate) fn from_texts(texts: &[&str]) -> InMemoryIndex {
+        InMemoryIndex::from_texts_with(&Analyzer::standard(), texts)
     }
 
-    // Concatenating strings using format! macro for better readability
-    format!(
-        "{}\x1b[31m{}\x1b[0m{}",
-        &text[..start_pos],         // Text before the highlight
-        &text[start_pos..=end_pos], // Text to be highlighted
-        &text[end_pos + 1..]        // Text after the highlight
-    )
-}
-
-fn vec_to_pathbuf(bytes: Vec<u8>) -> PathBuf {
-    let os_string = OsString::from_vec(bytes);
-    PathBuf::from(os_string)
+    /// Like `from_texts`, but built with `analyzer`.
+    pub(crate) fn from_texts_with(analyzer: &Analyzer, texts: &[&str]) -> InMemoryIndex {
+        let mut index = InMemoryIndex::with_analyzer(analyzer.name());
+        for (i, text) in texts.iter().enumerate() {
+            index.merge(InMemoryIndex::from_single_document(
+                analyzer,
+                i as u32 + 1,
+                PathBuf::from(format!("doc{}.txt", i + 1)),
+                text.to_string(),
+                SystemTime::UNIX_EPOCH,
+                false,
+            ));
+        }
+        index
+    }
 }
@@ -1,5 +1,21 @@
+pub mod analysis;
+pub mod checksum;
+pub mod codec;
+pub mod error;
+pub mod files;
+pub mod fingerprint;
+pub mod highlight;
 pub mod index;
 pub mod merge;
+pub mod mmap;
+pub mod progress;
+pub mod query;
 pub mod read;
+pub mod results;
+pub mod score;
+pub mod searcher;
+pub mod snippet;
+pub mod store;
 pub mod tmp;
+pub mod verify;
 pub mod write;
@@ -1,14 +1,33 @@
 use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
     fs::{self, File},
-    io::{self, BufWriter},
+    io::BufWriter,
     mem,
     path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{spawn, JoinHandle},
 };
 
-use crate::{read::IndexFileReader, tmp::TmpDir, write::IndexFileWriter};
+use tracing::debug;
+
+use crate::{
+    error::{IndexError, Result},
+    index::Document,
+    progress::Progress,
+    read::{IndexFileReader, StoredTextRef},
+    tmp::TmpDir,
+    write::IndexFileWriter,
+};
 
 /// Represents a merging tool for combining multiple index files into a single file.
 /// It uses a multi-level merging strategy to handle large numbers of files efficiently.
+///
+/// Merges run on a pool of background threads, so that adding a file returns
+/// as soon as the merges it triggers are queued.
 pub struct FileMerge {
     /// Directory where the final merged file will be stored.
     output_dir: PathBuf,
@@ -18,28 +37,55 @@ pub struct FileMerge {
 
     /// A vector of stacks, each containing files at different levels of merging.
     /// Each inner vector represents a level in the multi-level merge process.
-    stacks: Vec<Vec<PathBuf>>,
+    /// Files that are still being merged are represented by the receiving end
+    /// of their merge.
+    stacks: Vec<Vec<MergedFile>>,
+
+    /// How many files to merge at a time, at most.
+    nstreams: usize,
+
+    /// The threads that run the merges.
+    pool: MergePool,
+
+    /// Where finished merges are recorded.
+    progress: Progress,
 }
 
-// How many file to merge at a time, at most.
-const NSTREAMS: usize = 8;
+/// The default number of files to merge at a time, at most.
+pub const DEFAULT_NSTREAMS: usize = 8;
 
 // The final file name.
 const MERGED_FILENAME: &str = "index.bat";
 
 impl FileMerge {
-    pub fn new(output_dir: &Path) -> FileMerge {
+    /// Creates a `FileMerge` that merges `nstreams` files at a time (at least
+    /// 2) on `threads` background threads. With no threads, every merge runs
+    /// on the calling thread, as soon as it is triggered. Every finished merge
+    /// is recorded in `progress`.
+    pub fn new(
+        output_dir: &Path,
+        nstreams: usize,
+        threads: usize,
+        progress: Progress,
+    ) -> FileMerge {
+        assert!(nstreams >= 2, "cannot merge fewer than 2 files at a time");
         FileMerge {
             output_dir: output_dir.to_owned(),
             tmp_dir: TmpDir::new(output_dir),
             stacks: vec![],
+            nstreams,
+            pool: MergePool::new(threads),
+            progress,
         }
     }
 
     /// Adds a file to the merge process.
     /// Files are added to a multi-level stack structure
     /// where they are progressively merged with other files.
-    pub fn add_file(&mut self, mut file: PathBuf) -> io::Result<()> {
+    ///
+    /// Files must be added in order of the documents they contain.
+    pub fn add_file(&mut self, file: PathBuf) -> Result<()> {
+        let mut file = MergedFile::Ready(file);
         let mut level = 0;
         loop {
             // Ensure the current level exists in the stacks vector.
@@ -51,119 +97,265 @@ impl FileMerge {
             self.stacks[level].push(file);
 
             // Merge files at this level if the stack is full.
-            if self.stacks[level].len() < NSTREAMS {
+            if self.stacks[level].len() < self.nstreams {
                 break;
             }
 
-            // Create a new file to store the merged result and update the stack.
-            let (filename, out) = self.tmp_dir.create()?;
-            let mut to_merge = vec![];
-            mem::swap(&mut self.stacks[level], &mut to_merge);
-            merge_streams(to_merge, out)?;
-            file = filename;
+            // Queue a merge into a new file and move it up to the next level.
+            let to_merge = mem::take(&mut self.stacks[level]);
             level += 1;
+            file = self.merge(to_merge, level)?;
         }
         Ok(())
     }
 
     /// Completes the merge process by merging all remaining files.
     /// This method should be called after all files have been added.
-    pub fn finish(mut self) -> io::Result<()> {
-        let mut tmp = Vec::with_capacity(NSTREAMS);
-        for stack in self.stacks {
+    pub fn finish(mut self) -> Result<()> {
+        let mut tmp = Vec::with_capacity(self.nstreams);
+        // Each merge includes the output of the previous one, so it is one
+        // level higher.
+        let mut level = self.stacks.len();
+        for stack in mem::take(&mut self.stacks) {
             for file in stack.into_iter().rev() {
                 tmp.push(file);
-                if tmp.len() == NSTREAMS {
-                    merge_reversed(&mut tmp, &mut self.tmp_dir)?;
+                if tmp.len() == self.nstreams {
+                    self.merge_reversed(&mut tmp, level)?;
+                    level += 1;
                 }
             }
         }
 
         if tmp.len() > 1 {
-            merge_reversed(&mut tmp, &mut self.tmp_dir)?;
+            self.merge_reversed(&mut tmp, level)?;
+        }
+        assert!(tmp.len() <= 1);
+        let last_file = tmp.pop();
+        // Wait for every merge to finish, even if one of them failed.
+        self.pool.join();
+        match last_file {
+            Some(last_file) => {
+                fs::rename(last_file.wait()?, self.output_dir.join(MERGED_FILENAME))?;
+                Ok(())
+            }
+            None => Err(IndexError::NoDocuments),
+        }
+    }
+
+    /// Reverses the order of files and then queues a merge of them into one,
+    /// updating the original list of files with the result.
+    fn merge_reversed(&mut self, files: &mut Vec<MergedFile>, level: usize) -> Result<()> {
+        files.reverse();
+        let to_merge = mem::replace(files, Vec::with_capacity(self.nstreams));
+        files.push(self.merge(to_merge, level)?);
+        Ok(())
+    }
+
+    /// Queues a merge of `files`, in order, into a new file at `level`.
+    fn merge(&mut self, files: Vec<MergedFile>, level: usize) -> Result<MergedFile> {
+        let progress = self.progress.clone();
+        let count = files.len();
+        self.pool.merge(files, &mut self.tmp_dir, move || {
+            debug!(files = count, level, "merged index files");
+            progress.merge_done(level as u64);
+        })
+    }
+}
+
+/// An index file waiting to be merged, which may itself be the output of a
+/// merge that hasn't finished yet.
+enum MergedFile {
+    Ready(PathBuf),
+    Pending(Receiver<Result<PathBuf>>),
+}
+
+impl MergedFile {
+    /// Waits for the file to be written, and returns its path.
+    fn wait(self) -> Result<PathBuf> {
+        match self {
+            MergedFile::Ready(path) => Ok(path),
+            MergedFile::Pending(receiver) => {
+                receiver.recv().unwrap_or(Err(IndexError::MergeThread))
+            }
+        }
+    }
+}
+
+type MergeJob = Box<dyn FnOnce() + Send>;
+
+/// A pool of threads that run merges in the order they are queued.
+///
+/// A merge waits for the merges that produce its input files, which are
+/// always queued before it. Since every thread takes the oldest merge in the
+/// queue, those merges have all been started (or are finished) by the time it
+/// runs, so waiting for them can't deadlock.
+struct MergePool {
+    jobs: Option<Sender<MergeJob>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl MergePool {
+    fn new(threads: usize) -> MergePool {
+        let (sender, receiver) = channel::<MergeJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..threads)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                spawn(move || loop {
+                    // Only hold the lock while waiting for the next merge.
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        MergePool {
+            jobs: if threads.is_empty() {
+                None
+            } else {
+                Some(sender)
+            },
+            threads,
         }
-        assert!(tmp.len() == 1);
-        match tmp.pop() {
-            Some(last_file) => fs::rename(last_file, self.output_dir.join(MERGED_FILENAME)),
-            None => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "no ducuments were parsed or none contained any words",
-            )),
+    }
+
+    /// Queues a merge of `files`, in order, into a new temporary file, calling
+    /// `done` once it has been written.
+    fn merge<F>(&self, files: Vec<MergedFile>, tmp_dir: &mut TmpDir, done: F) -> Result<MergedFile>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let (filename, out) = tmp_dir.create()?;
+        let (sender, receiver) = channel();
+        let job = move || {
+            let result = files
+                .into_iter()
+                .map(MergedFile::wait)
+                .collect::<Result<Vec<_>>>()
+                .and_then(|files| merge_streams(files, out))
+                .map(|()| {
+                    done();
+                    filename
+                });
+            let _ = sender.send(result);
+        };
+        match &self.jobs {
+            Some(jobs) => jobs
+                .send(Box::new(job))
+                .map_err(|_| IndexError::MergeThread)?,
+            None => job(),
+        }
+        Ok(MergedFile::Pending(receiver))
+    }
+
+    /// Waits for every queued merge to finish.
+    fn join(&mut self) {
+        self.jobs = None;
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
         }
     }
 }
 
+impl Drop for MergePool {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
 /// Merges multiple index files into a single output file.
 /// This function reads through all the provided index files,
 /// combines their contents based on the lexicographical order of index terms,
 /// and writes the merged output to a new file.
 ///
-/// It uses a multi-way merge algorithm, similar to that used in merge sort, to efficiently combine the files.
-fn merge_streams(files: Vec<PathBuf>, out: BufWriter<File>) -> io::Result<()> {
+/// It uses a multi-way merge algorithm, similar to that used in merge sort, to
+/// efficiently combine the files: a min-heap holds the next term of every
+/// stream, so finding the next term to write takes O(log k) time for k
+/// streams. The files must be given in order of the documents they contain,
+/// since the postings of a term are copied from each stream in that order.
+fn merge_streams(files: Vec<PathBuf>, out: BufWriter<File>) -> Result<()> {
     let mut streams: Vec<IndexFileReader> = files
         .into_iter()
         .map(|p| IndexFileReader::open_and_delete(p, true))
-        .collect::<io::Result<_>>()?;
+        .collect::<Result<_>>()?;
 
-    let mut output = IndexFileWriter::new(out)?;
+    let analyzer = streams[0].analyzer().to_string();
+    if let Some(other) = streams.iter().find(|s| s.analyzer() != analyzer) {
+        return Err(IndexError::AnalyzerMismatch {
+            index: analyzer,
+            other: other.analyzer().to_string(),
+        });
+    }
+    let mut output = IndexFileWriter::new(out, &analyzer)?;
+    if streams.iter().any(IndexFileReader::has_stored_text) {
+        output.store_text();
+    }
 
-    let mut point: u64 = 0;
-    let mut count = streams.iter().filter(|s| s.peek().is_some()).count();
-    while count > 0 {
-        let mut term = None;
-        let mut nbytes = 0;
-        let mut df = 0;
-        for s in &streams {
-            match s.peek() {
-                None => {}
-                Some(entry) => {
-                    if entry.term.is_empty() {
-                        term = Some(entry.term.clone());
-                        nbytes = entry.nbytes;
-                        df = entry.df;
-                        break;
-                    }
-                    if term.is_none() || entry.term < *term.as_ref().unwrap() {
-                        term = Some(entry.term.clone());
-                        nbytes = entry.nbytes;
-                        df = entry.df
-                    } else if entry.term == *term.as_ref().unwrap() {
-                        nbytes += entry.nbytes;
-                        df += entry.df
-                    }
-                }
+    // The next term of each stream, along with the index of the stream. Ties
+    // pop in order of stream index.
+    let mut heap: BinaryHeap<Reverse<(String, usize)>> = streams
+        .iter()
+        .enumerate()
+        .filter_map(|(i, s)| s.peek().map(|entry| Reverse((entry.term.clone(), i))))
+        .collect();
+
+    let mut at_term = Vec::with_capacity(streams.len());
+    while let Some(Reverse((term, i))) = heap.pop() {
+        // Gather every other stream that is at the same term.
+        at_term.clear();
+        at_term.push(i);
+        while let Some(Reverse((next, j))) = heap.peek() {
+            if *next != term {
+                break;
             }
+            at_term.push(*j);
+            heap.pop();
         }
 
-        let term = term.expect("bug in algorithm");
-
-        for s in &mut streams {
-            if s.is_at(&term) {
-                s.move_entry_to(&mut output)?;
-                if s.peek().is_none() {
-                    count -= 1;
-                }
-                if term.is_empty() {
-                    break;
-                }
+        let point = output.offset();
+        let mut df = 0;
+        for &j in &at_term {
+            let s = &mut streams[j];
+            df += s.peek().expect("bug in algorithm").df;
+            s.move_entry_to(&mut output)?;
+            if let Some(entry) = s.peek() {
+                heap.push(Reverse((entry.term.clone(), j)));
             }
         }
 
+        // Re-encoding the first posting of each stream may change the size of
+        // the postings.
+        let nbytes = output.offset() - point;
         output.write_contents_entry(term, df, point, nbytes);
-        point += nbytes
     }
 
-    Ok(())
-}
+    // The documents go after the data of every term. Each stream's documents
+    // are sorted by id, so they are merged the same way as the terms, and
+    // read one at a time the same way too. Stored text is copied one
+    // document at a time, still compressed.
+    let mut next_docs: Vec<Option<(Document, Option<StoredTextRef>)>> = streams
+        .iter_mut()
+        .map(IndexFileReader::next_document)
+        .collect::<Result<_>>()?;
+    let mut heap: BinaryHeap<Reverse<(u32, usize)>> = BinaryHeap::with_capacity(streams.len());
+    for (i, doc) in next_docs.iter().enumerate() {
+        if let Some((doc, _)) = doc {
+            heap.push(Reverse((doc.id, i)));
+        }
+    }
+    while let Some(Reverse((_, i))) = heap.pop() {
+        let next = streams[i].next_document()?;
+        let (doc, text) = mem::replace(&mut next_docs[i], next).expect("bug in algorithm");
+        let text = text
+            .map(|text| streams[i].read_stored_text(doc.id, &text))
+            .transpose()?;
+        output.write_document_with_text(&doc, text.as_deref())?;
+        if let Some((next, _)) = &next_docs[i] {
+            heap.push(Reverse((next.id, i)));
+        }
+    }
 
-/// Reverses the order of files and then merges them into one,
-/// updating the original list of filenames with the result.
-fn merge_reversed(filenames: &mut Vec<PathBuf>, tmp_dir: &mut TmpDir) -> io::Result<()> {
-    filenames.reverse();
-    let (merge_filename, out) = tmp_dir.create()?;
-    let mut to_merge = Vec::with_capacity(NSTREAMS);
-    mem::swap(filenames, &mut to_merge);
-    merge_streams(to_merge, out)?;
-    filenames.push(merge_filename);
-    Ok(())
+    output.finish()
 }
@@ -0,0 +1,328 @@
+//! Memory-mapped index files.
+//!
+//! `IndexFileReader` makes a single forward pass over an index file, which is
+//! what merging needs. Searching needs random access instead: `MmapIndexReader`
+//! maps the whole file into memory and hands out slices of it without copying,
+//! and since it never mutates anything, it can be shared by many threads.
+
+use std::{fs::File, io::Cursor, path::Path};
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use memmap2::Mmap;
+
+use crate::{
+    checksum::checksum,
+    error::{IndexError, Result},
+    read::{DocRecord, Entry, IndexHeader, StoredTextRef},
+    write::DOC_TABLE_RECORD_SIZE,
+};
+
+/// Random access to an index file through a read-only memory map.
+pub struct MmapIndexReader {
+    mmap: Mmap,
+    header: IndexHeader,
+}
+
+/// An entry in the table of entries, borrowed from a memory-mapped file.
+/// See `Entry`.
+#[derive(Debug, Clone, Copy)]
+pub struct EntryRef<'a> {
+    pub term: &'a str,
+    pub df: u32,
+    pub offset: u64,
+    pub nbytes: u64,
+    pub crc: u32,
+    pub last_doc: u32,
+}
+
+impl EntryRef<'_> {
+    /// Copy this entry into an owned `Entry`.
+    pub fn to_entry(&self) -> Entry {
+        Entry {
+            term: self.term.to_string(),
+            df: self.df,
+            offset: self.offset,
+            nbytes: self.nbytes,
+            crc: self.crc,
+            last_doc: self.last_doc,
+        }
+    }
+}
+
+/// A sample of the term index: a term of the table of entries and the
+/// position of its entry, which starts a block of entries.
+#[derive(Debug, Clone)]
+pub struct TermIndexSample {
+    pub term: String,
+    /// Position of the entry relative to the start of the table of entries.
+    pub position: u64,
+    /// Checksum of the block of entries from this sample to the next one.
+    pub block_crc: u32,
+}
+
+/// An iterator over the entries of a table of entries.
+pub struct Entries<'a> {
+    data: &'a [u8],
+    /// Offset in the file of the start of `data`.
+    offset: u64,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<EntryRef<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        Some(self.read_entry())
+    }
+}
+
+impl<'a> Entries<'a> {
+    fn read_entry(&mut self) -> Result<EntryRef<'a>> {
+        const FIXED_SIZE: usize = 8 + 8 + 4 + 4 + 4 + 4;
+        let truncated = IndexError::Truncated {
+            offset: self.offset,
+        };
+        if self.data.len() < FIXED_SIZE {
+            self.data = &[];
+            return Err(truncated);
+        }
+        let offset = LittleEndian::read_u64(&self.data[0..]);
+        let nbytes = LittleEndian::read_u64(&self.data[8..]);
+        let df = LittleEndian::read_u32(&self.data[16..]);
+        let crc = LittleEndian::read_u32(&self.data[20..]);
+        let last_doc = LittleEndian::read_u32(&self.data[24..]);
+        let term_len = LittleEndian::read_u32(&self.data[28..]) as usize;
+        let Some(term) = self.data.get(FIXED_SIZE..FIXED_SIZE + term_len) else {
+            self.data = &[];
+            return Err(truncated);
+        };
+        let Ok(term) = std::str::from_utf8(term) else {
+            self.data = &[];
+            return Err(IndexError::InvalidUtf8Term {
+                offset: self.offset,
+            });
+        };
+        self.data = &self.data[FIXED_SIZE + term_len..];
+        self.offset += (FIXED_SIZE + term_len) as u64;
+        Ok(EntryRef {
+            term,
+            df,
+            offset,
+            nbytes,
+            crc,
+            last_doc,
+        })
+    }
+}
+
+impl MmapIndexReader {
+    /// Map an index file into memory and read its header.
+    pub fn open<P: AsRef<Path>>(filename: P) -> Result<MmapIndexReader> {
+        let file = File::open(filename)?;
+        // SAFETY: index files are never modified after `IndexFileWriter`
+        // finishes them. Modifying a file while it is mapped is a bug.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let header = IndexHeader::read_from(&mut &mmap[..])?;
+        if header.term_index_offset > mmap.len() as u64 {
+            return Err(IndexError::Truncated {
+                offset: header.term_index_offset,
+            });
+        }
+        Ok(MmapIndexReader { mmap, header })
+    }
+
+    /// Name of the analyzer that produced the terms in the index.
+    pub fn analyzer(&self) -> &str {
+        &self.header.analyzer
+    }
+
+    /// Total number of tokens in all documents of the index.
+    pub fn word_count(&self) -> u64 {
+        self.header.word_count
+    }
+
+    /// Number of documents in the index.
+    pub fn doc_count(&self) -> u64 {
+        self.header.doc_count as u64
+    }
+
+    /// Number of entries in the table of entries.
+    pub fn term_count(&self) -> u32 {
+        self.header.term_count
+    }
+
+    /// Format version of the file.
+    pub fn version(&self) -> u32 {
+        self.header.version
+    }
+
+    /// Optional features used by the file; see `SUPPORTED_FLAGS`.
+    pub fn flags(&self) -> u32 {
+        self.header.flags
+    }
+
+    /// The names and offsets of the sections of the file whose checksum does
+    /// not match the one in the header.
+    pub fn damaged_sections(&self) -> Vec<(&'static str, u64)> {
+        let header = &self.header;
+        let sections = [
+            (
+                "table of entries",
+                header.entries_offset,
+                self.entries_table(),
+                header.entries_crc,
+            ),
+            (
+                "table of documents",
+                header.docs_offset,
+                self.doc_table(),
+                header.docs_crc,
+            ),
+            (
+                "term index",
+                header.term_index_offset,
+                self.term_index(),
+                header.term_index_crc,
+            ),
+        ];
+        sections
+            .into_iter()
+            .filter(|&(_, _, data, crc)| checksum(data) != crc)
+            .map(|(name, offset, _, _)| (name, offset))
+            .collect()
+    }
+
+    /// The raw bytes of the table of entries.
+    pub fn entries_table(&self) -> &[u8] {
+        &self.mmap[self.header.entries_offset as usize..self.header.docs_offset as usize]
+    }
+
+    /// Iterate over the table of entries, starting `position` bytes into it.
+    /// `position` must be the start of an entry, such as a position stored in
+    /// the term index.
+    pub fn entries_from(&self, position: u64) -> Entries<'_> {
+        let table = self.entries_table();
+        Entries {
+            data: table.get(position as usize..).unwrap_or(&[]),
+            offset: self.header.entries_offset + position,
+        }
+    }
+
+    /// The block of the table of entries that starts at `samples[i]`, after
+    /// checking it against the checksum stored in the sample.
+    pub fn entries_block(&self, samples: &[TermIndexSample], i: usize) -> Result<Entries<'_>> {
+        let table = self.entries_table();
+        let start = samples[i].position;
+        let end = samples
+            .get(i + 1)
+            .map_or(table.len() as u64, |next| next.position);
+        let offset = self.header.entries_offset + start;
+        let data = table
+            .get(start as usize..end as usize)
+            .ok_or(IndexError::Truncated { offset })?;
+        if checksum(data) != samples[i].block_crc {
+            return Err(IndexError::checksum_mismatch(
+                format!(
+                    "block of table of entries starting at term {:?}",
+                    samples[i].term
+                ),
+                offset,
+            ));
+        }
+        Ok(Entries { data, offset })
+    }
+
+    /// The raw bytes of the table of documents.
+    pub fn doc_table(&self) -> &[u8] {
+        &self.mmap[self.header.docs_offset as usize..self.header.term_index_offset as usize]
+    }
+
+    /// The raw bytes of the term index.
+    pub fn term_index(&self) -> &[u8] {
+        &self.mmap[self.header.term_index_offset as usize..]
+    }
+
+    /// Offset of the term index in the file.
+    pub fn term_index_offset(&self) -> u64 {
+        self.header.term_index_offset
+    }
+
+    /// Decode the term index, after checking its checksum.
+    pub fn term_index_samples(&self) -> Result<Vec<TermIndexSample>> {
+        let data = self.term_index();
+        if checksum(data) != self.header.term_index_crc {
+            return Err(IndexError::checksum_mismatch(
+                "term index",
+                self.header.term_index_offset,
+            ));
+        }
+        let mut cursor = Cursor::new(data);
+        let mut samples = vec![];
+        while (cursor.position() as usize) < data.len() {
+            let sample_offset = self.header.term_index_offset + cursor.position();
+            let truncated = |_| IndexError::Truncated {
+                offset: sample_offset,
+            };
+            let position = cursor.read_u64::<LittleEndian>().map_err(truncated)?;
+            let block_crc = cursor.read_u32::<LittleEndian>().map_err(truncated)?;
+            let term_len = cursor.read_u32::<LittleEndian>().map_err(truncated)? as usize;
+            let start = cursor.position() as usize;
+            let term = data
+                .get(start..start + term_len)
+                .ok_or(IndexError::Truncated {
+                    offset: sample_offset,
+                })?;
+            let term = std::str::from_utf8(term)
+                .map_err(|_| IndexError::InvalidUtf8Term {
+                    offset: sample_offset,
+                })?
+                .to_string();
+            cursor.set_position((start + term_len) as u64);
+            samples.push(TermIndexSample {
+                term,
+                position,
+                block_crc,
+            });
+        }
+        Ok(samples)
+    }
+
+    /// The hits of a term, given the `offset` and `nbytes` of its entry. The
+    /// checksum of the hits is not checked.
+    pub fn postings(&self, offset: u64, nbytes: u64) -> Result<&[u8]> {
+        self.slice(offset, nbytes)
+    }
+
+    /// The `i`th record of the table of documents. Only the checksum of the
+    /// record itself is checked.
+    pub fn doc_table_record(&self, i: u64) -> Result<DocRecord> {
+        let offset = self.header.docs_offset + i * DOC_TABLE_RECORD_SIZE;
+        if i >= self.doc_count() {
+            return Err(IndexError::Truncated { offset });
+        }
+        let mut record = self.slice(offset, DOC_TABLE_RECORD_SIZE)?;
+        DocRecord::read_from(&mut record, offset)
+    }
+
+    /// The data of the document described by `record`. The checksum of the
+    /// data is not checked.
+    pub fn document_data(&self, record: &DocRecord) -> Result<&[u8]> {
+        self.slice(record.offset, record.nbytes)
+    }
+
+    /// The compressed stored text at `text`, from the data of a document. Its
+    /// checksum is not checked.
+    pub fn stored_text_data(&self, text: &StoredTextRef) -> Result<&[u8]> {
+        self.slice(text.offset, text.nbytes)
+    }
+
+    /// `nbytes` bytes of the file starting at `offset`.
+    fn slice(&self, offset: u64, nbytes: u64) -> Result<&[u8]> {
+        offset
+            .checked_add(nbytes)
+            .and_then(|end| self.mmap.get(offset as usize..end as usize))
+            .ok_or(IndexError::Truncated { offset })
+    }
+}
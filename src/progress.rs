@@ -0,0 +1,253 @@
+//! Progress of index creation.
+//!
+//! The stages of `create` update a shared `Progress` as they go, and a
+//! `ProgressReporter` thread periodically renders it: as a progress bar when
+//! writing to a terminal, and as one JSON object per line otherwise, for
+//! other programs to consume.
+
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Counters describing how far index creation has got. Cloning a `Progress`
+/// gives another handle to the same counters, so it can be shared between
+/// threads.
+#[derive(Clone, Default)]
+pub struct Progress {
+    counters: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    documents_read: AtomicU64,
+    documents_total: AtomicU64,
+    bytes_indexed: AtomicU64,
+    bytes_total: AtomicU64,
+    tmp_files_written: AtomicU64,
+    merges_done: AtomicU64,
+    merge_level: AtomicU64,
+}
+
+/// The values of the counters of a `Progress` at some point in time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProgressSnapshot {
+    /// Number of documents loaded into memory.
+    pub documents_read: u64,
+    /// Number of documents to index.
+    pub documents_total: u64,
+    /// Number of bytes of text tokenized.
+    pub bytes_indexed: u64,
+    /// Number of bytes of text to tokenize.
+    pub bytes_total: u64,
+    /// Number of temporary index files written.
+    pub tmp_files_written: u64,
+    /// Number of merges of index files finished.
+    pub merges_done: u64,
+    /// Highest level of the merges finished so far: files written by a merge
+    /// of temporary files are at level 1, files written by a merge of those at
+    /// level 2, and so on.
+    pub merge_level: u64,
+}
+
+impl Progress {
+    /// Start tracking the indexing of `documents_total` documents, of
+    /// `bytes_total` bytes in all.
+    pub fn new(documents_total: u64, bytes_total: u64) -> Progress {
+        let progress = Progress::default();
+        let counters = &progress.counters;
+        counters
+            .documents_total
+            .store(documents_total, Ordering::Relaxed);
+        counters.bytes_total.store(bytes_total, Ordering::Relaxed);
+        progress
+    }
+
+    /// Record that a document was loaded into memory.
+    pub fn document_read(&self) {
+        self.counters.documents_read.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that `bytes` bytes of text were tokenized.
+    pub fn text_indexed(&self, bytes: u64) {
+        self.counters
+            .bytes_indexed
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Record that a temporary index file was written.
+    pub fn tmp_file_written(&self) {
+        self.counters
+            .tmp_files_written
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a merge wrote a file at the given `level`.
+    pub fn merge_done(&self, level: u64) {
+        self.counters.merges_done.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .merge_level
+            .fetch_max(level, Ordering::Relaxed);
+    }
+
+    /// Read all the counters.
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let c = &self.counters;
+        ProgressSnapshot {
+            documents_read: c.documents_read.load(Ordering::Relaxed),
+            documents_total: c.documents_total.load(Ordering::Relaxed),
+            bytes_indexed: c.bytes_indexed.load(Ordering::Relaxed),
+            bytes_total: c.bytes_total.load(Ordering::Relaxed),
+            tmp_files_written: c.tmp_files_written.load(Ordering::Relaxed),
+            merges_done: c.merges_done.load(Ordering::Relaxed),
+            merge_level: c.merge_level.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ProgressSnapshot {
+    /// Estimate the time left to tokenize the remaining text, assuming it goes
+    /// as fast as it has so far. Returns `None` before any text has been
+    /// tokenized.
+    pub fn eta(&self, elapsed: Duration) -> Option<Duration> {
+        if self.bytes_indexed == 0 {
+            return None;
+        }
+        let remaining = self.bytes_total.saturating_sub(self.bytes_indexed);
+        Some(elapsed.mul_f64(remaining as f64 / self.bytes_indexed as f64))
+    }
+}
+
+/// How a `ProgressReporter` renders progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressStyle {
+    /// A progress bar, redrawn in place. Meant for terminals.
+    Bar,
+    /// One JSON object per line, at longer intervals. Meant for logs and
+    /// other programs.
+    JsonLines,
+}
+
+impl ProgressStyle {
+    fn interval(self) -> Duration {
+        match self {
+            ProgressStyle::Bar => Duration::from_millis(100),
+            ProgressStyle::JsonLines => Duration::from_secs(1),
+        }
+    }
+}
+
+/// A thread that writes the state of a `Progress` to stderr at regular
+/// intervals, until `finish` is called.
+pub struct ProgressReporter {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl ProgressReporter {
+    /// Start reporting `progress` in the given `style`.
+    pub fn start(progress: Progress, style: ProgressStyle) -> ProgressReporter {
+        let (stop, stopped) = channel();
+        let handle = spawn(move || {
+            let start = Instant::now();
+            let mut done = false;
+            while !done {
+                done = !matches!(
+                    stopped.recv_timeout(style.interval()),
+                    Err(RecvTimeoutError::Timeout)
+                );
+                let line = render(style, &progress.snapshot(), start.elapsed(), done);
+                // Progress is only informative; don't fail if stderr is gone.
+                let _ = io::stderr().write_all(line.as_bytes());
+            }
+        });
+        ProgressReporter { stop, handle }
+    }
+
+    /// Report the final state of the progress, and stop.
+    pub fn finish(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.join();
+    }
+}
+
+fn render(style: ProgressStyle, s: &ProgressSnapshot, elapsed: Duration, done: bool) -> String {
+    let eta = s.eta(elapsed);
+    match style {
+        ProgressStyle::Bar => {
+            const WIDTH: u64 = 30;
+            let filled = match s.bytes_total {
+                0 => WIDTH,
+                total => WIDTH * s.bytes_indexed.min(total) / total,
+            };
+            let eta = match eta {
+                Some(eta) if !done => format!(", ETA {}", format_duration(eta)),
+                _ => String::new(),
+            };
+            format!(
+                "\r[{}{}] {}/{} documents, {}/{}, {} temporary files, merge level {}, {}{}\x1b[K{}",
+                "#".repeat(filled as usize),
+                "-".repeat((WIDTH - filled) as usize),
+                s.documents_read,
+                s.documents_total,
+                format_bytes(s.bytes_indexed),
+                format_bytes(s.bytes_total),
+                s.tmp_files_written,
+                s.merge_level,
+                format_duration(elapsed),
+                eta,
+                if done { "\n" } else { "" }
+            )
+        }
+        ProgressStyle::JsonLines => {
+            let eta = match eta {
+                Some(eta) => format!("{:.3}", eta.as_secs_f64()),
+                None => "null".to_string(),
+            };
+            format!(
+                "{{\"elapsed_secs\":{:.3},\"documents_read\":{},\"documents_total\":{},\
+                 \"bytes_indexed\":{},\"bytes_total\":{},\"tmp_files_written\":{},\
+                 \"merges_done\":{},\"merge_level\":{},\"eta_secs\":{},\"done\":{}}}\n",
+                elapsed.as_secs_f64(),
+                s.documents_read,
+                s.documents_total,
+                s.bytes_indexed,
+                s.bytes_total,
+                s.tmp_files_written,
+                s.merges_done,
+                s.merge_level,
+                eta,
+                done
+            )
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
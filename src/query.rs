@@ -0,0 +1,774 @@
+//! Search queries.
+//!
+//! A query is a boolean expression over terms, such as
+//! `rust AND (async OR tokio) NOT unsafe`. The operators `AND`, `OR` and
+//! `NOT` must be written in upper case; anything else is a term. Two terms
+//! next to each other with no operator in between must both match, as if
+//! joined by `AND`. `NOT` binds tighter than `AND`, which binds tighter
+//! than `OR`.
+//!
+//! Text in double quotes, like `"exact phrase"`, matches documents where the
+//! terms appear next to each other, in order. `rust NEAR/3 safe` matches
+//! documents where the two terms appear, in either order, with at most 3
+//! other tokens between them; `NEAR/0` matches adjacent terms. `NEAR/k`
+//! binds tighter than every other operator.
+//!
+//! Queries are evaluated against posting lists that are sorted by document
+//! id, so every operator is a linear merge of sorted lists.
+
+use crate::{
+    analysis::Analyzer,
+    error::{IndexError, Result},
+};
+
+/// A parsed search query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// Documents containing the term.
+    Term(String),
+
+    /// Documents matching every subquery.
+    And(Vec<Query>),
+
+    /// Documents matching at least one subquery.
+    Or(Vec<Query>),
+
+    /// Documents not matching the subquery.
+    Not(Box<Query>),
+
+    /// Documents containing the terms at the given positions relative to the
+    /// first one, which is at 0. The terms of a phrase are usually at
+    /// consecutive positions, but the analyzer may leave gaps where it drops
+    /// tokens, like stop words.
+    Phrase(Vec<(String, u32)>),
+
+    /// Documents containing both terms with at most `distance` other
+    /// positions between them.
+    Near {
+        left: String,
+        right: String,
+        distance: u32,
+    },
+}
+
+/// Something a `Query` can be evaluated against, such as an index.
+pub trait DocSet {
+    /// The ids of all documents containing `term`, in increasing order.
+    fn term_docs(&self, term: &str) -> Vec<u32>;
+
+    /// The token ordinals of every occurrence of `term` in the document, in
+    /// increasing order.
+    fn term_positions(&self, term: &str, document_id: u32) -> Vec<u32>;
+
+    /// The ids of all documents, in increasing order.
+    fn all_docs(&self) -> Vec<u32>;
+}
+
+impl Query {
+    /// Parse a query, breaking every term in it into index terms with
+    /// `analyzer`.
+    ///
+    /// A word of the query that the analyzer breaks into several terms
+    /// matches documents containing those terms as a phrase.
+    pub fn parse(text: &str, analyzer: &Analyzer) -> Result<Query> {
+        let tokens = lex(text)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+            analyzer,
+        };
+        let query = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(query),
+            Some(token) => Err(parse_error(format!("unexpected {}", token))),
+        }
+    }
+
+    /// The ids of all documents matching this query, in increasing order.
+    pub fn matching_docs<D: DocSet + ?Sized>(&self, docs: &D) -> Vec<u32> {
+        match self {
+            Query::Term(term) => docs.term_docs(term),
+            Query::Or(queries) => queries
+                .iter()
+                .map(|q| q.matching_docs(docs))
+                .reduce(|a, b| union(&a, &b))
+                .unwrap_or_default(),
+            Query::Not(query) => difference(&docs.all_docs(), &query.matching_docs(docs)),
+            Query::And(queries) => {
+                // Subtract negated subqueries instead of computing their
+                // complement, which would touch every document.
+                let (negative, positive): (Vec<&Query>, Vec<&Query>) =
+                    queries.iter().partition(|q| matches!(q, Query::Not(_)));
+                let mut result = positive
+                    .iter()
+                    .map(|q| q.matching_docs(docs))
+                    .reduce(|a, b| intersect(&a, &b))
+                    .unwrap_or_else(|| docs.all_docs());
+                for q in negative {
+                    if let Query::Not(q) = q {
+                        result = difference(&result, &q.matching_docs(docs));
+                    }
+                }
+                result
+            }
+            Query::Phrase(terms) => {
+                let candidates = terms
+                    .iter()
+                    .map(|(term, _)| docs.term_docs(term))
+                    .reduce(|a, b| intersect(&a, &b))
+                    .unwrap_or_default();
+                candidates
+                    .into_iter()
+                    .filter(|&doc| {
+                        let positions: Vec<(Vec<u32>, u32)> = terms
+                            .iter()
+                            .map(|(term, offset)| (docs.term_positions(term, doc), *offset))
+                            .collect();
+                        contains_phrase(&positions)
+                    })
+                    .collect()
+            }
+            Query::Near {
+                left,
+                right,
+                distance,
+            } => intersect(&docs.term_docs(left), &docs.term_docs(right))
+                .into_iter()
+                .filter(|&doc| {
+                    within_distance(
+                        &docs.term_positions(left, doc),
+                        &docs.term_positions(right, doc),
+                        *distance,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// All terms in this query, including negated ones.
+    pub fn terms(&self) -> Vec<&str> {
+        match self {
+            Query::Term(term) => vec![term],
+            Query::And(queries) | Query::Or(queries) => {
+                queries.iter().flat_map(|q| q.terms()).collect()
+            }
+            Query::Not(query) => query.terms(),
+            Query::Phrase(terms) => terms.iter().map(|(term, _)| term.as_str()).collect(),
+            Query::Near { left, right, .. } => vec![left, right],
+        }
+    }
+
+    /// True if evaluating this query needs the ids of all documents, as
+    /// `DocSet::all_docs`. Only a `NOT` does, and only if it isn't part of an
+    /// `AND` with a positive subquery, which subtracts the negated documents
+    /// from those instead.
+    pub fn needs_all_docs(&self) -> bool {
+        match self {
+            Query::Not(_) => true,
+            Query::Or(queries) => queries.iter().any(Query::needs_all_docs),
+            Query::And(queries) => {
+                let mut positive = false;
+                for q in queries {
+                    match q {
+                        Query::Not(q) if q.needs_all_docs() => return true,
+                        Query::Not(_) => {}
+                        q if q.needs_all_docs() => return true,
+                        _ => positive = true,
+                    }
+                }
+                !positive
+            }
+            _ => false,
+        }
+    }
+
+    /// All terms that contribute to a document matching this query, i.e. the
+    /// terms that are not negated. These are the terms worth highlighting.
+    pub fn positive_terms(&self) -> Vec<&str> {
+        let mut terms = vec![];
+        self.collect_positive_terms(&mut terms);
+        terms
+    }
+
+    fn collect_positive_terms<'a>(&'a self, terms: &mut Vec<&'a str>) {
+        match self {
+            Query::Term(term) => terms.push(term),
+            Query::And(queries) | Query::Or(queries) => {
+                for q in queries {
+                    q.collect_positive_terms(terms);
+                }
+            }
+            Query::Phrase(phrase) => terms.extend(phrase.iter().map(|(term, _)| term.as_str())),
+            Query::Near { left, right, .. } => {
+                terms.push(left);
+                terms.push(right);
+            }
+            Query::Not(_) => {}
+        }
+    }
+}
+
+/// True if there is a position `p` in the first list of `positions` such
+/// that `p + offset` is in every list, along with its offset. Every list must
+/// be sorted, and the first offset must be 0.
+fn contains_phrase(positions: &[(Vec<u32>, u32)]) -> bool {
+    let Some(((first, _), rest)) = positions.split_first() else {
+        return false;
+    };
+    first.iter().any(|&start| {
+        rest.iter().all(|(list, offset)| {
+            start
+                .checked_add(*offset)
+                .is_some_and(|p| list.binary_search(&p).is_ok())
+        })
+    })
+}
+
+/// True if some element of `a` and a different element of `b` have at most
+/// `distance` other positions between them. Both lists must be sorted, and
+/// may be the same list: a term is only near another occurrence of itself.
+fn within_distance(a: &[u32], b: &[u32], distance: u32) -> bool {
+    let distance = distance.saturating_add(1);
+    let near = |x: u32, y: u32| x != y && x.abs_diff(y) <= distance;
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            // The same position is not a match, but the next one of either
+            // list may be. Anything after those is farther.
+            if a.get(i + 1).is_some_and(|&next| near(next, b[j]))
+                || b.get(j + 1).is_some_and(|&next| near(a[i], next))
+            {
+                return true;
+            }
+            i += 1;
+            j += 1;
+        } else if near(a[i], b[j]) {
+            return true;
+        } else if a[i] < b[j] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    false
+}
+
+/// Documents present in both sorted lists.
+pub fn intersect(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut res = Vec::with_capacity(a.len().min(b.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                res.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    res
+}
+
+/// Documents present in either sorted list.
+pub fn union(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut res = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => {
+                res.push(a[i]);
+                i += 1;
+            }
+            std::cmp::Ordering::Greater => {
+                res.push(b[j]);
+                j += 1;
+            }
+            std::cmp::Ordering::Equal => {
+                res.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    res.extend_from_slice(&a[i..]);
+    res.extend_from_slice(&b[j..]);
+    res
+}
+
+/// Documents present in the sorted list `a` but not in `b`.
+pub fn difference(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut res = Vec::with_capacity(a.len());
+    let mut j = 0;
+    for &doc in a {
+        while j < b.len() && b[j] < doc {
+            j += 1;
+        }
+        if j == b.len() || b[j] != doc {
+            res.push(doc);
+        }
+    }
+    res
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum QueryToken {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Near(u32),
+    Word(String),
+    Phrase(String),
+}
+
+impl std::fmt::Display for QueryToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryToken::LParen => write!(f, "'('"),
+            QueryToken::RParen => write!(f, "')'"),
+            QueryToken::And => write!(f, "AND"),
+            QueryToken::Or => write!(f, "OR"),
+            QueryToken::Not => write!(f, "NOT"),
+            QueryToken::Near(distance) => write!(f, "NEAR/{}", distance),
+            QueryToken::Word(word) => write!(f, "{:?}", word),
+            QueryToken::Phrase(phrase) => write!(f, "\"{}\"", phrase),
+        }
+    }
+}
+
+/// Split a query into parentheses, operators, words and quoted phrases.
+fn lex(text: &str) -> Result<Vec<QueryToken>> {
+    let mut tokens = vec![];
+    let mut word = String::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch.is_whitespace() || ch == '(' || ch == ')' || ch == '"' {
+            if !word.is_empty() {
+                tokens.push(word_token(std::mem::take(&mut word))?);
+            }
+            match ch {
+                '(' => tokens.push(QueryToken::LParen),
+                ')' => tokens.push(QueryToken::RParen),
+                '"' => {
+                    let mut phrase = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some(ch) => phrase.push(ch),
+                            None => return Err(parse_error("missing closing '\"'".to_string())),
+                        }
+                    }
+                    tokens.push(QueryToken::Phrase(phrase));
+                }
+                _ => {}
+            }
+        } else {
+            word.push(ch);
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word_token(word)?);
+    }
+    Ok(tokens)
+}
+
+/// An operator, or a word to search for. `NEAR/` must be followed by a
+/// distance.
+fn word_token(word: String) -> Result<QueryToken> {
+    Ok(match word.as_str() {
+        "AND" => QueryToken::And,
+        "OR" => QueryToken::Or,
+        "NOT" => QueryToken::Not,
+        _ => match word.strip_prefix("NEAR/").map(str::parse) {
+            Some(Ok(distance)) => QueryToken::Near(distance),
+            Some(Err(_)) => {
+                return Err(parse_error(format!(
+                    "{:?} is not NEAR/ followed by a distance",
+                    word
+                )))
+            }
+            None => QueryToken::Word(word),
+        },
+    })
+}
+
+/// A recursive descent parser over the output of `lex`.
+struct Parser<'a> {
+    tokens: &'a [QueryToken],
+    pos: usize,
+    analyzer: &'a Analyzer,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&QueryToken> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&QueryToken> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    /// or := and ("OR" and)*
+    fn parse_or(&mut self) -> Result<Query> {
+        let mut queries = vec![self.parse_and()?];
+        while self.peek() == Some(&QueryToken::Or) {
+            self.pos += 1;
+            queries.push(self.parse_and()?);
+        }
+        Ok(flatten(queries, Query::Or))
+    }
+
+    /// and := near (["AND"] near | "NOT" near)*
+    fn parse_and(&mut self) -> Result<Query> {
+        let mut queries = vec![self.parse_near()?];
+        loop {
+            match self.peek() {
+                Some(QueryToken::And) => {
+                    self.pos += 1;
+                    queries.push(self.parse_near()?);
+                }
+                // `a NOT b` means `a AND NOT b`; `parse_unary` handles the NOT.
+                Some(
+                    QueryToken::Not
+                    | QueryToken::LParen
+                    | QueryToken::Word(_)
+                    | QueryToken::Phrase(_),
+                ) => {
+                    queries.push(self.parse_near()?);
+                }
+                _ => break,
+            }
+        }
+        Ok(flatten(queries, Query::And))
+    }
+
+    /// near := unary ("NEAR/k" unary)?
+    fn parse_near(&mut self) -> Result<Query> {
+        let left = self.parse_unary()?;
+        let distance = match self.peek() {
+            Some(QueryToken::Near(distance)) => *distance,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.parse_unary()?;
+        match (left, right) {
+            (Query::Term(left), Query::Term(right)) => Ok(Query::Near {
+                left,
+                right,
+                distance,
+            }),
+            _ => Err(parse_error(format!(
+                "both sides of NEAR/{} must be single terms",
+                distance
+            ))),
+        }
+    }
+
+    /// unary := "NOT" unary | "(" or ")" | word | phrase
+    fn parse_unary(&mut self) -> Result<Query> {
+        match self.next().cloned() {
+            Some(QueryToken::Not) => Ok(Query::Not(Box::new(self.parse_unary()?))),
+            Some(QueryToken::LParen) => {
+                let query = self.parse_or()?;
+                match self.next() {
+                    Some(QueryToken::RParen) => Ok(query),
+                    _ => Err(parse_error("missing ')'".to_string())),
+                }
+            }
+            // A word the analyzer splits into several terms, like `don't`,
+            // is searched for as a phrase.
+            Some(QueryToken::Word(text) | QueryToken::Phrase(text)) => {
+                let tokens = self.analyzer.analyze(&text);
+                let Some(first) = tokens.first().map(|token| token.position) else {
+                    return Err(parse_error(format!(
+                        "{:?} contains no searchable terms",
+                        text
+                    )));
+                };
+                let mut terms: Vec<(String, u32)> = tokens
+                    .into_iter()
+                    .map(|token| (token.text, token.position - first))
+                    .collect();
+                match terms.len() {
+                    1 => Ok(Query::Term(terms.pop().unwrap().0)),
+                    _ => Ok(Query::Phrase(terms)),
+                }
+            }
+            Some(token) => Err(parse_error(format!("unexpected {}", token))),
+            None => Err(parse_error("unexpected end of query".to_string())),
+        }
+    }
+}
+
+/// Combine `queries` with `op`, unless there is only one of them.
+fn flatten(mut queries: Vec<Query>, op: fn(Vec<Query>) -> Query) -> Query {
+    if queries.len() == 1 {
+        queries.pop().unwrap()
+    } else {
+        op(queries)
+    }
+}
+
+fn parse_error(msg: String) -> IndexError {
+    IndexError::QueryParse(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analysis::{Token, TokenFilter},
+        index::InMemoryIndex,
+    };
+
+    fn parse(text: &str) -> Result<Query> {
+        Query::parse(text, &Analyzer::standard())
+    }
+
+    fn term(term: &str) -> Query {
+        Query::Term(term.to_string())
+    }
+
+    fn not(query: Query) -> Query {
+        Query::Not(Box::new(query))
+    }
+
+    /// A phrase of terms at consecutive positions.
+    fn phrase(terms: &[&str]) -> Query {
+        Query::Phrase(
+            terms
+                .iter()
+                .zip(0..)
+                .map(|(term, offset)| (term.to_string(), offset))
+                .collect(),
+        )
+    }
+
+    fn near(left: &str, right: &str, distance: u32) -> Query {
+        Query::Near {
+            left: left.to_string(),
+            right: right.to_string(),
+            distance,
+        }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let expected = Query::Or(vec![
+            term("rust"),
+            Query::And(vec![term("go"), term("zig")]),
+        ]);
+        assert_eq!(parse("rust OR go AND zig").unwrap(), expected);
+        let expected = Query::Or(vec![
+            Query::And(vec![term("rust"), term("go")]),
+            term("zig"),
+        ]);
+        assert_eq!(parse("rust AND go OR zig").unwrap(), expected);
+    }
+
+    #[test]
+    fn words_without_an_operator_are_and() {
+        let expected = Query::And(vec![term("rust"), term("safe"), term("fast")]);
+        assert_eq!(parse("rust safe fast").unwrap(), expected);
+        assert_eq!(parse("rust AND safe fast").unwrap(), expected);
+        // Terms are analyzed like the documents were.
+        assert_eq!(parse("Rust, SAFE! fast?").unwrap(), expected);
+    }
+
+    #[test]
+    fn not_applies_to_the_next_operand() {
+        assert_eq!(parse("NOT rust").unwrap(), not(term("rust")));
+        assert_eq!(parse("NOT NOT rust").unwrap(), not(not(term("rust"))));
+        assert_eq!(
+            parse("rust NOT go").unwrap(),
+            Query::And(vec![term("rust"), not(term("go"))])
+        );
+        assert_eq!(
+            parse("NOT rust OR go").unwrap(),
+            Query::Or(vec![not(term("rust")), term("go")])
+        );
+    }
+
+    #[test]
+    fn parentheses_group() {
+        assert_eq!(
+            parse("(rust OR go) zig").unwrap(),
+            Query::And(vec![Query::Or(vec![term("rust"), term("go")]), term("zig")])
+        );
+        assert_eq!(
+            parse("NOT (rust OR go)").unwrap(),
+            not(Query::Or(vec![term("rust"), term("go")]))
+        );
+        assert_eq!(parse("((rust))").unwrap(), term("rust"));
+    }
+
+    #[test]
+    fn quotes_make_phrases() {
+        assert_eq!(
+            parse("\"Rust is safe\"").unwrap(),
+            phrase(&["rust", "is", "safe"])
+        );
+        assert_eq!(parse("\"rust\"").unwrap(), term("rust"));
+        assert_eq!(
+            parse("\"rust is\" OR go").unwrap(),
+            Query::Or(vec![phrase(&["rust", "is"]), term("go")])
+        );
+        // A word the analyzer splits is a phrase too.
+        assert_eq!(parse("don't").unwrap(), phrase(&["don", "t"]));
+    }
+
+    #[test]
+    fn near_binds_tighter_than_everything_else() {
+        assert_eq!(parse("rust NEAR/3 safe").unwrap(), near("rust", "safe", 3));
+        assert_eq!(
+            parse("go OR rust NEAR/0 safe fast").unwrap(),
+            Query::Or(vec![
+                term("go"),
+                Query::And(vec![near("rust", "safe", 0), term("fast")])
+            ])
+        );
+        assert_eq!(
+            parse("NOT (rust NEAR/2 safe)").unwrap(),
+            not(near("rust", "safe", 2))
+        );
+    }
+
+    #[test]
+    fn malformed_queries_are_parse_errors() {
+        for text in [
+            "",
+            "(rust",
+            "rust)",
+            "(rust OR go",
+            "()",
+            "rust AND",
+            "rust OR",
+            "OR rust",
+            "AND rust",
+            "rust NOT",
+            "rust NEAR/x safe",
+            "rust NEAR/ safe",
+            "rust NEAR/-1 safe",
+            "rust NEAR/3",
+            "NEAR/3 safe",
+            "\"rust safe\" NEAR/3 go",
+            "(rust OR go) NEAR/3 safe",
+            "NOT rust NEAR/3 safe",
+            "\"rust safe",
+            "!!!",
+        ] {
+            match parse(text) {
+                Err(IndexError::QueryParse(_)) => {}
+                other => panic!("{:?} parsed as {:?}", text, other),
+            }
+        }
+    }
+
+    #[test]
+    fn only_negations_without_positive_terms_need_all_docs() {
+        for text in [
+            "rust",
+            "rust NOT unsafe",
+            "rust NOT go NOT zig",
+            "(rust OR go) NOT zig",
+        ] {
+            assert!(!parse(text).unwrap().needs_all_docs(), "{}", text);
+        }
+        for text in [
+            "NOT unsafe",
+            "NOT rust NOT go",
+            "rust OR NOT go",
+            "rust NOT (go OR NOT zig)",
+            "rust (go OR NOT zig)",
+        ] {
+            assert!(parse(text).unwrap().needs_all_docs(), "{}", text);
+        }
+    }
+
+    fn matching(index: &InMemoryIndex, text: &str) -> Vec<u32> {
+        parse(text).unwrap().matching_docs(index)
+    }
+
+    #[test]
+    fn phrases_match_consecutive_tokens_not_bytes() {
+        let index =
+            InMemoryIndex::from_texts(&["rust ... --- ;;; !!! safe", "rust is safe", "safe, rust"]);
+        // Punctuation puts bytes between tokens, not token positions.
+        assert_eq!(matching(&index, "\"rust safe\""), [1]);
+        assert_eq!(matching(&index, "\"rust is safe\""), [2]);
+        assert_eq!(matching(&index, "\"safe rust\""), [3]);
+        assert_eq!(matching(&index, "\"is rust\""), [] as [u32; 0]);
+    }
+
+    #[test]
+    fn phrases_with_repeated_terms() {
+        let index = InMemoryIndex::from_texts(&["safe rust rust go safe", "rust go rust"]);
+        assert_eq!(matching(&index, "\"rust rust go\""), [1]);
+        assert_eq!(matching(&index, "\"rust go\""), [1, 2]);
+        assert_eq!(matching(&index, "\"go rust\""), [2]);
+        assert_eq!(matching(&index, "\"rust rust rust\""), [] as [u32; 0]);
+        assert_eq!(matching(&index, "\"rust safe\""), [] as [u32; 0]);
+        assert_eq!(matching(&index, "\"go safe\""), [1]);
+    }
+
+    /// Drops a few stop words, leaving gaps in token positions.
+    struct StopWords;
+
+    impl TokenFilter for StopWords {
+        fn filter(&self, token: Token) -> Option<Token> {
+            (!matches!(token.text.as_str(), "is" | "a" | "the")).then_some(token)
+        }
+    }
+
+    #[test]
+    fn phrases_keep_the_gaps_of_dropped_tokens() {
+        let analyzer = Analyzer::standard().with_filter(StopWords);
+        let index = InMemoryIndex::from_texts_with(
+            &analyzer,
+            &[
+                "rust is safe",
+                "rust safe",
+                "rust is a safe bet",
+                "the rust is safe",
+            ],
+        );
+        let query = Query::parse("\"Rust is safe\"", &analyzer).unwrap();
+        assert_eq!(
+            query,
+            Query::Phrase(vec![("rust".into(), 0), ("safe".into(), 2)])
+        );
+        // Any stop word fills the gap, but only one.
+        assert_eq!(query.matching_docs(&index), [1, 4]);
+        let query = Query::parse("\"the rust\"", &analyzer).unwrap();
+        assert_eq!(query, term("rust"));
+        let query = Query::parse("\"rust a the safe\"", &analyzer).unwrap();
+        assert_eq!(query.matching_docs(&index), [3]);
+    }
+
+    #[test]
+    fn near_counts_tokens_between_terms() {
+        let index = InMemoryIndex::from_texts(&[
+            "rust ........................................ safe",
+            "rust is very very very safe",
+            "safe and then rust",
+            "rust rust rust",
+            "rust and rust",
+        ]);
+        assert_eq!(matching(&index, "rust NEAR/0 safe"), [1]);
+        assert_eq!(matching(&index, "rust NEAR/1 safe"), [1]);
+        assert_eq!(matching(&index, "rust NEAR/2 safe"), [1, 3]);
+        assert_eq!(matching(&index, "safe NEAR/2 rust"), [1, 3]);
+        assert_eq!(matching(&index, "rust NEAR/3 safe"), [1, 3]);
+        assert_eq!(matching(&index, "rust NEAR/4 safe"), [1, 2, 3]);
+        // A term is only near another occurrence of itself.
+        assert_eq!(matching(&index, "rust NEAR/0 rust"), [4]);
+        assert_eq!(matching(&index, "rust NEAR/1 rust"), [4, 5]);
+        assert_eq!(matching(&index, "safe NEAR/9 safe"), [] as [u32; 0]);
+        assert_eq!(matching(&index, "rust NEAR/4294967295 safe"), [1, 2, 3]);
+    }
+}
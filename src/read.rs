@@ -1,14 +1,26 @@
 use std::{
+    ffi::OsString,
     fs::{self, File},
-    io::{self, BufReader, Read, Seek, SeekFrom},
-    path::Path,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
 };
 
 use byteorder::*;
 
 use byteorder::LittleEndian;
+use tracing::debug;
 
-use crate::write::IndexFileWriter;
+use crate::{
+    checksum::{checksum, extend_checksum, ChecksumReader},
+    error::{IndexError, Result},
+    fingerprint::Fingerprint,
+    index::Document,
+    write::{
+        IndexFileWriter, DOC_TABLE_RECORD_SIZE, FLAG_STORED_TEXT, FORMAT_VERSION, MAGIC,
+        SUPPORTED_FLAGS,
+    },
+};
 
 /// A `IndexFileReader` does a single linear pass over an index file from
 /// beginning to end. Needless to say, this is not how an index is normally
@@ -26,13 +38,162 @@ pub struct IndexFileReader {
 
     /// Reader that reads the table of entries. (Since this table is stored at
     /// the end of the file, we have to begin by `seek`ing to it; see the code
-    /// in `IndexFileReader::open_and_delete`.)
-    entries: BufReader<File>,
+    /// in `IndexFileReader::open_and_delete`.) It is limited to the table of
+    /// entries; the table of documents follows it. The checksum of the table
+    /// is checked once the last entry has been read.
+    entries: ChecksumReader<io::Take<BufReader<File>>>,
 
     /// The next entry in the table of contents, if any; or `None` if we've
     /// reached the end of the table. `IndexFileReader` always reads ahead one
     /// entry in the contents and stores it here.
     next: Option<Entry>,
+
+    /// The header of the file.
+    header: IndexHeader,
+
+    /// Number of records of the table of documents read so far, and their
+    /// checksum. The table follows the table of entries, and is read through
+    /// the same reader.
+    docs_read: u32,
+    docs_crc: u32,
+}
+
+/// The header at the beginning of every index file. See `IndexFileWriter`
+/// for the layout of the file.
+#[derive(Debug, Clone)]
+pub(crate) struct IndexHeader {
+    /// Format version of the file; see `FORMAT_VERSION`.
+    pub version: u32,
+    /// Optional features used by the file; see `SUPPORTED_FLAGS`.
+    pub flags: u32,
+    /// Offset of the table of entries.
+    pub entries_offset: u64,
+    /// Offset of the table of documents.
+    pub docs_offset: u64,
+    /// Offset of the term index.
+    pub term_index_offset: u64,
+    /// Number of documents in the table of documents.
+    pub doc_count: u32,
+    /// Number of entries in the table of entries.
+    pub term_count: u32,
+    /// Total number of tokens in all documents.
+    pub word_count: u64,
+    /// Checksum of the table of entries.
+    pub entries_crc: u32,
+    /// Checksum of the table of documents.
+    pub docs_crc: u32,
+    /// Checksum of the term index.
+    pub term_index_crc: u32,
+    /// Name of the analyzer that produced the terms.
+    pub analyzer: String,
+}
+
+impl IndexHeader {
+    /// Size of the header, not counting the analyzer name.
+    const FIXED_SIZE: u64 = 4 + 4 + 4 + 8 + 8 + 8 + 4 + 4 + 8 + 4 + 4 + 4 + 4 + 4;
+
+    /// Read a header, rejecting files that are not index files, that use a
+    /// format version or features this code does not understand, or whose
+    /// header is damaged.
+    pub(crate) fn read_from<R: Read>(f: &mut R) -> Result<IndexHeader> {
+        let mut f = ChecksumReader::new(f);
+        let f = &mut f;
+        let mut magic = [0; 4];
+        f.read_exact(&mut magic)
+            .map_err(|_| IndexError::NotAnIndexFile)?;
+        if magic != MAGIC {
+            return Err(IndexError::NotAnIndexFile);
+        }
+        let version = f.read_u32::<LittleEndian>()?;
+        if version != FORMAT_VERSION {
+            return Err(IndexError::UnsupportedVersion {
+                found: version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        let flags = f.read_u32::<LittleEndian>()?;
+        if flags & !SUPPORTED_FLAGS != 0 {
+            return Err(IndexError::UnsupportedFeatures { flags });
+        }
+        let entries_offset = f.read_u64::<LittleEndian>()?;
+        let docs_offset = f.read_u64::<LittleEndian>()?;
+        let term_index_offset = f.read_u64::<LittleEndian>()?;
+        let doc_count = f.read_u32::<LittleEndian>()?;
+        let term_count = f.read_u32::<LittleEndian>()?;
+        let word_count = f.read_u64::<LittleEndian>()?;
+        let entries_crc = f.read_u32::<LittleEndian>()?;
+        let docs_crc = f.read_u32::<LittleEndian>()?;
+        let term_index_crc = f.read_u32::<LittleEndian>()?;
+        let analyzer_len = f.read_u32::<LittleEndian>()? as u64;
+        // Don't trust the length until the checksum is verified.
+        let analyzer_offset = Self::FIXED_SIZE - 4;
+        let mut analyzer = vec![];
+        f.take(analyzer_len).read_to_end(&mut analyzer)?;
+        if analyzer.len() as u64 != analyzer_len {
+            return Err(IndexError::Truncated {
+                offset: analyzer_offset,
+            });
+        }
+        let crc = f.checksum();
+        if f.get_mut().read_u32::<LittleEndian>()? != crc {
+            return Err(IndexError::checksum_mismatch("header", 0));
+        }
+        let analyzer = String::from_utf8(analyzer).map_err(|_| IndexError::CorruptIndex {
+            what: "analyzer name is not valid UTF-8".to_string(),
+            offset: analyzer_offset,
+        })?;
+
+        if entries_offset > docs_offset
+            || docs_offset > term_index_offset
+            || term_index_offset - docs_offset != doc_count as u64 * DOC_TABLE_RECORD_SIZE
+        {
+            return Err(IndexError::CorruptIndex {
+                what: "header is inconsistent".to_string(),
+                offset: 0,
+            });
+        }
+
+        Ok(IndexHeader {
+            version,
+            flags,
+            entries_offset,
+            docs_offset,
+            term_index_offset,
+            doc_count,
+            term_count,
+            word_count,
+            entries_crc,
+            docs_crc,
+            term_index_crc,
+            analyzer,
+        })
+    }
+
+    /// Write the header, as read by `read_from`.
+    pub(crate) fn write_to<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(self.size() as usize);
+        buf.write_all(&MAGIC)?;
+        buf.write_u32::<LittleEndian>(self.version)?;
+        buf.write_u32::<LittleEndian>(self.flags)?;
+        buf.write_u64::<LittleEndian>(self.entries_offset)?;
+        buf.write_u64::<LittleEndian>(self.docs_offset)?;
+        buf.write_u64::<LittleEndian>(self.term_index_offset)?;
+        buf.write_u32::<LittleEndian>(self.doc_count)?;
+        buf.write_u32::<LittleEndian>(self.term_count)?;
+        buf.write_u64::<LittleEndian>(self.word_count)?;
+        buf.write_u32::<LittleEndian>(self.entries_crc)?;
+        buf.write_u32::<LittleEndian>(self.docs_crc)?;
+        buf.write_u32::<LittleEndian>(self.term_index_crc)?;
+        buf.write_u32::<LittleEndian>(self.analyzer.len() as u32)?;
+        buf.write_all(self.analyzer.as_bytes())?;
+        buf.write_u32::<LittleEndian>(checksum(&buf))?;
+        f.write_all(&buf)
+    }
+
+    /// Size of the header in bytes.
+    pub(crate) fn size(&self) -> u64 {
+        Self::FIXED_SIZE + self.analyzer.len() as u64
+    }
 }
 
 /// An entry in the table of entries of an index file.
@@ -40,6 +201,7 @@ pub struct IndexFileReader {
 /// Each entry in the table of entries is small. It consists of a string, the
 /// `term`; summary information about that term, as used in the corpus (`df`);
 /// and a pointer to bulkier data that tells more (`offset` and `nbytes`).
+#[derive(Debug, Clone)]
 pub struct Entry {
     /// The term is a word that appears in one or more documents in the corpus.
     /// The index file contains information about the documents that use this
@@ -54,6 +216,81 @@ pub struct Entry {
 
     /// Length of the index data for this term, in bytes.
     pub nbytes: u64,
+
+    /// Checksum of the index data for this term.
+    pub crc: u32,
+
+    /// Id of the last document in the index data for this term.
+    pub last_doc: u32,
+}
+
+/// A record in the table of documents of an index file, which points to the
+/// data of one document.
+#[derive(Debug, Clone, Copy)]
+pub struct DocRecord {
+    /// Id of the document.
+    pub id: u32,
+
+    /// Number of tokens in the document, so that ranking doesn't need to
+    /// read its data.
+    pub length: u32,
+
+    /// Offset of the document data from the beginning of the file, in bytes.
+    pub offset: u64,
+
+    /// Length of the document data, in bytes.
+    pub nbytes: u64,
+
+    /// Checksum of the document data.
+    pub crc: u32,
+}
+
+/// Where the compressed text of a document is in the document store of an
+/// index file; see `FLAG_STORED_TEXT`.
+#[derive(Debug, Clone, Copy)]
+pub struct StoredTextRef {
+    /// Offset of the compressed text from the beginning of the file, in bytes.
+    pub offset: u64,
+
+    /// Length of the compressed text, in bytes.
+    pub nbytes: u64,
+
+    /// Checksum of the compressed text.
+    pub crc: u32,
+}
+
+impl DocRecord {
+    /// Read a record, as written by `write_to`, that starts at `offset` in
+    /// the file, checking its checksum.
+    pub(crate) fn read_from<R: Read>(f: &mut R, offset: u64) -> Result<DocRecord> {
+        let mut buf = [0; DOC_TABLE_RECORD_SIZE as usize - 4];
+        f.read_exact(&mut buf)?;
+        if f.read_u32::<LittleEndian>()? != checksum(&buf) {
+            return Err(IndexError::checksum_mismatch(
+                "record of table of documents",
+                offset,
+            ));
+        }
+        let mut buf = &buf[..];
+        Ok(DocRecord {
+            id: buf.read_u32::<LittleEndian>()?,
+            length: buf.read_u32::<LittleEndian>()?,
+            offset: buf.read_u64::<LittleEndian>()?,
+            nbytes: buf.read_u64::<LittleEndian>()?,
+            crc: buf.read_u32::<LittleEndian>()?,
+        })
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(DOC_TABLE_RECORD_SIZE as usize);
+        buf.write_u32::<LittleEndian>(self.id)?;
+        buf.write_u32::<LittleEndian>(self.length)?;
+        buf.write_u64::<LittleEndian>(self.offset)?;
+        buf.write_u64::<LittleEndian>(self.nbytes)?;
+        buf.write_u32::<LittleEndian>(self.crc)?;
+        buf.write_u32::<LittleEndian>(checksum(&buf))?;
+        f.write_all(&buf)
+    }
 }
 
 impl IndexFileReader {
@@ -63,27 +300,26 @@ impl IndexFileReader {
     ///
     /// - `filename`: path to the index file.
     /// - `delete`: whether to delete the file after opening.
-    pub fn open_and_delete<P: AsRef<Path>>(
-        filename: P,
-        delete: bool,
-    ) -> io::Result<IndexFileReader> {
+    pub fn open_and_delete<P: AsRef<Path>>(filename: P, delete: bool) -> Result<IndexFileReader> {
         let filename = filename.as_ref();
         let mut terms_docs_raw = File::open(filename)?;
 
         // header
-        let entries_offset = terms_docs_raw.read_u64::<LittleEndian>()?;
-        println!(
-            "opened {}, table of entries starts at {}",
-            filename.display(),
-            entries_offset
+        let header = IndexHeader::read_from(&mut terms_docs_raw)?;
+        debug!(
+            path = %filename.display(),
+            entries_offset = header.entries_offset,
+            "opened index file"
         );
 
         let mut entries_raw = File::open(filename)?;
-        entries_raw.seek(SeekFrom::Start(entries_offset))?;
+        entries_raw.seek(SeekFrom::Start(header.entries_offset))?;
         let terms_docs = BufReader::new(terms_docs_raw);
-        let mut entries = BufReader::new(entries_raw);
+        let mut entries = ChecksumReader::new(
+            BufReader::new(entries_raw).take(header.docs_offset - header.entries_offset),
+        );
 
-        let first = IndexFileReader::read_entry(&mut entries)?;
+        let first = Self::read_next_entry(&mut entries, &header)?;
 
         if delete {
             fs::remove_file(filename)?;
@@ -93,42 +329,183 @@ impl IndexFileReader {
             terms_docs,
             entries,
             next: first,
+            header,
+            docs_read: 0,
+            docs_crc: 0,
         })
     }
 
-    /// Read the next entry from the table of contents.
+    /// Read the next entry from the table of contents, which starts at
+    /// `position` in the file.
+    ///
+    /// The checksum of the table is only checked once its end is reached, so
+    /// the lengths in the entry are checked against the sections they point
+    /// into before anything is allocated for them.
     ///
-    /// Returns `Ok(None)` if we have reached the end of the file.
-    fn read_entry(f: &mut BufReader<File>) -> io::Result<Option<Entry>> {
+    /// Returns `Ok(None)` if we have reached the end of the table.
+    fn read_entry<R: Read>(
+        f: &mut R,
+        position: u64,
+        header: &IndexHeader,
+    ) -> Result<Option<Entry>> {
+        const FIXED_SIZE: u64 = 8 + 8 + 4 + 4 + 4 + 4;
         let offset = match f.read_u64::<LittleEndian>() {
             Ok(value) => value,
             Err(err) => {
                 if err.kind() == io::ErrorKind::UnexpectedEof {
                     return Ok(None);
                 } else {
-                    return Err(err);
+                    return Err(err.into());
                 }
             }
         };
 
         let nbytes = f.read_u64::<LittleEndian>()?;
         let df = f.read_u32::<LittleEndian>()?;
-        let term_len = f.read_u32::<LittleEndian>()? as usize;
-        let mut bytes = vec![0; term_len];
+        let crc = f.read_u32::<LittleEndian>()?;
+        let last_doc = f.read_u32::<LittleEndian>()?;
+        let term_len = f.read_u32::<LittleEndian>()? as u64;
+        if term_len > header.docs_offset.saturating_sub(position + FIXED_SIZE) {
+            return Err(IndexError::CorruptIndex {
+                what: "term longer than the rest of the table of entries".to_string(),
+                offset: position,
+            });
+        }
+        let mut bytes = vec![0; term_len as usize];
         f.read_exact(&mut bytes)?;
         let term = match String::from_utf8(bytes) {
             Ok(s) => s,
-            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "unicode fail")),
+            Err(_) => return Err(IndexError::InvalidUtf8Term { offset: position }),
         };
+        // The hits of every term come before the table of entries.
+        if offset
+            .checked_add(nbytes)
+            .is_none_or(|end| end > header.entries_offset)
+        {
+            return Err(IndexError::CorruptIndex {
+                what: format!("hits of term {:?} are out of bounds", term),
+                offset: position,
+            });
+        }
 
         Ok(Some(Entry {
             term,
             df,
             offset,
             nbytes,
+            crc,
+            last_doc,
         }))
     }
 
+    /// Read the next entry from the table of contents, checking the checksum
+    /// of the whole table once its end is reached.
+    fn read_next_entry(
+        entries: &mut ChecksumReader<io::Take<BufReader<File>>>,
+        header: &IndexHeader,
+    ) -> Result<Option<Entry>> {
+        let position = header.docs_offset - entries.get_mut().limit();
+        let entry = Self::read_entry(entries, position, header)?;
+        if entry.is_none() && entries.checksum() != header.entries_crc {
+            return Err(IndexError::checksum_mismatch(
+                "table of entries",
+                header.entries_offset,
+            ));
+        }
+        Ok(entry)
+    }
+
+    /// Name of the analyzer that produced the terms in this file.
+    pub fn analyzer(&self) -> &str {
+        &self.header.analyzer
+    }
+
+    /// Total number of tokens in all documents of this file.
+    pub fn word_count(&self) -> u64 {
+        self.header.word_count
+    }
+
+    /// True if the file stores the text of its documents.
+    pub fn has_stored_text(&self) -> bool {
+        self.header.flags & FLAG_STORED_TEXT != 0
+    }
+
+    /// Read every document of the file, with its stored text, in increasing
+    /// order of document id.
+    ///
+    /// This must be called after every entry has been read, since the table of
+    /// documents follows the table of entries; otherwise it returns
+    /// `IndexError::ReadOutOfOrder`.
+    pub fn read_documents(&mut self) -> Result<Vec<Document>> {
+        let mut docs = Vec::with_capacity(self.header.doc_count as usize);
+        while let Some((mut doc, text)) = self.next_document()? {
+            if let Some(text) = text {
+                doc.stored = Some(self.read_stored_text(doc.id, &text)?);
+            }
+            docs.push(doc);
+        }
+        Ok(docs)
+    }
+
+    /// Read the next document of the file, in increasing order of document
+    /// id, but leave its stored text in the file: the document comes with
+    /// where its text is, if it is stored, to be read with
+    /// `read_stored_text`. Returns `Ok(None)` once every document has been
+    /// read, after checking the checksum of the whole table of documents.
+    ///
+    /// Like `read_documents`, this must be called after every entry has been
+    /// read.
+    pub(crate) fn next_document(&mut self) -> Result<Option<(Document, Option<StoredTextRef>)>> {
+        if self.next.is_some() {
+            return Err(IndexError::ReadOutOfOrder(
+                "documents read before all entries",
+            ));
+        }
+        if self.docs_read == self.header.doc_count {
+            if self.docs_crc != self.header.docs_crc {
+                return Err(IndexError::checksum_mismatch(
+                    "table of documents",
+                    self.header.docs_offset,
+                ));
+            }
+            return Ok(None);
+        }
+        let mut buf = [0; DOC_TABLE_RECORD_SIZE as usize];
+        self.entries.get_mut().get_mut().read_exact(&mut buf)?;
+        self.docs_crc = extend_checksum(self.docs_crc, &buf);
+        let record = DocRecord::read_from(
+            &mut &buf[..],
+            self.header.docs_offset + self.docs_read as u64 * DOC_TABLE_RECORD_SIZE,
+        )?;
+        self.docs_read += 1;
+
+        let mut data = vec![0; record.nbytes as usize];
+        self.terms_docs.seek(SeekFrom::Start(record.offset))?;
+        self.terms_docs.read_exact(&mut data)?;
+        if checksum(&data) != record.crc {
+            return Err(IndexError::checksum_mismatch(
+                format!("document {}", record.id),
+                record.offset,
+            ));
+        }
+        Ok(Some(read_document(&mut &data[..], self.header.flags)?))
+    }
+
+    /// Read the compressed stored text of document `id`, without
+    /// decompressing it.
+    pub(crate) fn read_stored_text(&mut self, id: u32, text: &StoredTextRef) -> Result<Vec<u8>> {
+        let mut data = vec![0; text.nbytes as usize];
+        self.terms_docs.seek(SeekFrom::Start(text.offset))?;
+        self.terms_docs.read_exact(&mut data)?;
+        if checksum(&data) != text.crc {
+            return Err(IndexError::checksum_mismatch(
+                format!("stored text of document {}", id),
+                text.offset,
+            ));
+        }
+        Ok(data)
+    }
+
     /// Borrow a reference to the next entry in the table of contents.
     /// (Since we always read ahead one entry, this method can't fail.)
     ///
@@ -138,12 +515,15 @@ impl IndexFileReader {
     }
 
     /// Advances the reader to the next entry and returns the current entry.
-    pub fn iter_next_entry(&mut self) -> Option<Entry> {
-        let res = self.next.take();
-        if let Ok(n) = Self::read_entry(&mut self.entries) {
-            self.next = n
-        }
-        res
+    ///
+    /// Fails if the entry after it can't be read, or if the table of entries
+    /// is damaged (checked once its end is reached).
+    pub fn iter_next_entry(&mut self) -> Result<Option<Entry>> {
+        let Some(res) = self.next.take() else {
+            return Ok(None);
+        };
+        self.next = Self::read_next_entry(&mut self.entries, &self.header)?;
+        Ok(Some(res))
     }
 
     /// True if the next entry is for the given term.
@@ -156,25 +536,74 @@ impl IndexFileReader {
 
     /// Copy the current entry to the specified output stream, then read the
     /// header for the next entry.
-    pub fn move_entry_to(&mut self, out: &mut IndexFileWriter) -> io::Result<()> {
+    ///
+    /// Returns `IndexError::ReadOutOfOrder` if every entry has already been
+    /// read.
+    pub fn move_entry_to(&mut self, out: &mut IndexFileWriter) -> Result<()> {
         {
-            let e = self.next.as_ref();
-            if e.is_none() {
-                return Err(io::Error::new(io::ErrorKind::Other, "no entry to move"));
-            }
-            let e = e.unwrap();
-            if e.nbytes > usize::max_value() as u64 {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "computer not big enough to hold index entry",
-                ));
-            }
+            let e = self
+                .next
+                .as_ref()
+                .ok_or(IndexError::ReadOutOfOrder("no entry to move"))?;
             let mut buf = vec![0; e.nbytes as usize];
             self.terms_docs.read_exact(&mut buf)?;
-            out.write_main(&buf)?;
+            if checksum(&buf) != e.crc {
+                return Err(IndexError::checksum_mismatch(
+                    format!("hits of term {:?}", e.term),
+                    e.offset,
+                ));
+            }
+            out.copy_postings(&buf, e.offset, e.last_doc)?;
         }
 
-        self.next = Self::read_entry(&mut self.entries)?;
+        self.next = Self::read_next_entry(&mut self.entries, &self.header)?;
         Ok(())
     }
 }
+
+/// Read the data of a single document, as written by
+/// `IndexFileWriter::write_document` to a file with the given `flags`, along
+/// with where its stored text is, if it has any. The stored text itself is
+/// not read, so `stored` is always `None`.
+pub(crate) fn read_document<R: Read>(
+    f: &mut R,
+    flags: u32,
+) -> io::Result<(Document, Option<StoredTextRef>)> {
+    let id = f.read_u32::<LittleEndian>()?;
+    let length = f.read_u32::<LittleEndian>()?;
+    let fingerprint = Fingerprint {
+        size: f.read_u64::<LittleEndian>()?,
+        mtime: f.read_u64::<LittleEndian>()?,
+        hash: f.read_u64::<LittleEndian>()?,
+    };
+    let path = read_bytes(f)?;
+    let text = if flags & FLAG_STORED_TEXT != 0 {
+        Some(StoredTextRef {
+            offset: f.read_u64::<LittleEndian>()?,
+            nbytes: f.read_u64::<LittleEndian>()?,
+            crc: f.read_u32::<LittleEndian>()?,
+        })
+        .filter(|text| text.nbytes != 0)
+    } else {
+        None
+    };
+    let doc = Document {
+        id,
+        path: PathBuf::from(OsString::from_vec(path)),
+        length,
+        fingerprint,
+        stored: None,
+    };
+    Ok((doc, text))
+}
+
+/// Read a u64 length followed by that many bytes.
+fn read_bytes<R: Read>(f: &mut R) -> io::Result<Vec<u8>> {
+    let len = f.read_u64::<LittleEndian>()?;
+    let mut bytes = vec![];
+    f.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,77 @@
+//! Search results.
+//!
+//! Searching returns the best documents for a query as data: their ids,
+//! paths and scores, and where the terms of the query appear in them. How
+//! to show them, for example with the matches highlighted, is up to the
+//! caller.
+
+use std::{path::PathBuf, slice, vec};
+
+use crate::index::{Posting, TokenPos};
+
+/// The documents that best match a query, highest score first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+}
+
+/// A document matching a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub doc_id: u32,
+    pub path: PathBuf,
+    pub score: f32,
+
+    /// Where the terms of the query appear in the document, in increasing
+    /// order of `start_pos`. Terms under a `NOT` are not included.
+    pub matches: Vec<TokenPos>,
+}
+
+impl SearchResults {
+    /// True if no document matched the query.
+    pub fn is_empty(&self) -> bool {
+        self.hits.is_empty()
+    }
+
+    /// Number of documents returned.
+    pub fn len(&self) -> usize {
+        self.hits.len()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, SearchHit> {
+        self.hits.iter()
+    }
+}
+
+impl IntoIterator for SearchResults {
+    type Item = SearchHit;
+    type IntoIter = vec::IntoIter<SearchHit>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.hits.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a SearchResults {
+    type Item = &'a SearchHit;
+    type IntoIter = slice::Iter<'a, SearchHit>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.hits.iter()
+    }
+}
+
+/// All positions of the given postings of one document, sorted by
+/// `start_pos`. The same term may appear more than once in a query, so
+/// duplicates are removed.
+pub(crate) fn match_positions<'a>(
+    postings: impl IntoIterator<Item = &'a Posting>,
+) -> Vec<TokenPos> {
+    let mut matches: Vec<TokenPos> = postings
+        .into_iter()
+        .flat_map(|posting| posting.positions.iter().cloned())
+        .collect();
+    matches.sort_by_key(|pos| pos.start_pos);
+    matches.dedup_by_key(|pos| pos.start_pos);
+    matches
+}
@@ -0,0 +1,183 @@
+//! Relevance ranking.
+//!
+//! Documents matching a query are ranked with BM25, which rewards documents
+//! that contain the query terms often, favors rare terms over common ones,
+//! and discounts long documents.
+
+use std::cmp::Ordering;
+
+use crate::query::{DocSet, Query};
+
+/// The statistics about a corpus that BM25 needs.
+pub trait CorpusStats {
+    /// Total number of documents in the corpus.
+    fn doc_count(&self) -> u32;
+
+    /// Total number of tokens in all documents of the corpus.
+    fn total_doc_length(&self) -> u64;
+
+    /// Number of tokens in the document.
+    fn doc_length(&self, document_id: u32) -> u32;
+
+    /// Number of documents containing `term` (the `df` of its entry).
+    fn doc_freq(&self, term: &str) -> u32;
+}
+
+/// BM25 ranking parameters.
+#[derive(Debug, Clone, Copy)]
+pub struct Bm25 {
+    /// How quickly repeated occurrences of a term stop adding to the score,
+    /// from 0 (at once). Negative values make scores meaningless.
+    pub k1: f32,
+
+    /// How much the score is normalized by document length, from 0 (not at
+    /// all) to 1 (fully).
+    pub b: f32,
+}
+
+impl Default for Bm25 {
+    fn default() -> Self {
+        Bm25 { k1: 1.2, b: 0.75 }
+    }
+}
+
+/// A document matching a query, with its relevance score.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoredDoc {
+    pub document_id: u32,
+    pub score: f32,
+}
+
+impl Bm25 {
+    /// The contribution of a single term to a document's score.
+    ///
+    /// - `tf`: number of occurrences of the term in the document.
+    /// - `df`: number of documents containing the term.
+    /// - `doc_length`: number of tokens in the document.
+    /// - `avg_doc_length`: average number of tokens per document.
+    /// - `doc_count`: number of documents in the corpus.
+    pub fn term_score(
+        &self,
+        tf: u32,
+        df: u32,
+        doc_length: u32,
+        avg_doc_length: f32,
+        doc_count: u32,
+    ) -> f32 {
+        let (tf, df, n) = (tf as f32, df as f32, doc_count as f32);
+        let idf = (1.0 + (n - df + 0.5) / (df + 0.5)).ln();
+        let norm = if avg_doc_length > 0.0 {
+            1.0 - self.b + self.b * doc_length as f32 / avg_doc_length
+        } else {
+            1.0
+        };
+        idf * tf * (self.k1 + 1.0) / (tf + self.k1 * norm)
+    }
+
+    /// Find the documents matching `query` and return the `top_k` best ones,
+    /// highest score first. Documents with equal scores are ordered by id.
+    pub fn rank<S: DocSet + CorpusStats + ?Sized>(
+        &self,
+        query: &Query,
+        source: &S,
+        top_k: usize,
+    ) -> Vec<ScoredDoc> {
+        self.rank_matches(query, source, query.matching_docs(source), top_k)
+    }
+
+    /// Like `rank`, for callers that already found the documents matching
+    /// `query`: `matches` must be `query.matching_docs(source)`.
+    pub fn rank_matches<S: DocSet + CorpusStats + ?Sized>(
+        &self,
+        query: &Query,
+        source: &S,
+        matches: Vec<u32>,
+        top_k: usize,
+    ) -> Vec<ScoredDoc> {
+        let mut terms = query.positive_terms();
+        terms.sort();
+        terms.dedup();
+
+        let doc_count = source.doc_count();
+        let avg_doc_length = if doc_count == 0 {
+            0.0
+        } else {
+            source.total_doc_length() as f32 / doc_count as f32
+        };
+        let dfs: Vec<u32> = terms.iter().map(|term| source.doc_freq(term)).collect();
+
+        let mut scored: Vec<ScoredDoc> = matches
+            .into_iter()
+            .map(|document_id| {
+                let doc_length = source.doc_length(document_id);
+                let score = terms
+                    .iter()
+                    .zip(&dfs)
+                    .map(|(term, &df)| {
+                        let tf = source.term_positions(term, document_id).len() as u32;
+                        if tf == 0 {
+                            0.0
+                        } else {
+                            self.term_score(tf, df, doc_length, avg_doc_length, doc_count)
+                        }
+                    })
+                    // An empty `sum` of floats is -0.0, which would show as
+                    // such for queries with no positive terms.
+                    .fold(0.0, |total, score| total + score);
+                ScoredDoc { document_id, score }
+            })
+            .collect();
+
+        let by_rank = |a: &ScoredDoc, b: &ScoredDoc| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(Ordering::Equal)
+                .then(a.document_id.cmp(&b.document_id))
+        };
+        if scored.len() > top_k {
+            if top_k > 0 {
+                scored.select_nth_unstable_by(top_k - 1, by_rank);
+            }
+            scored.truncate(top_k);
+        }
+        scored.sort_by(by_rank);
+        scored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analysis::Analyzer, index::InMemoryIndex};
+
+    fn rank(texts: &[&str], query: &str, top_k: usize) -> Vec<ScoredDoc> {
+        let index = InMemoryIndex::from_texts(texts);
+        let query = Query::parse(query, &Analyzer::standard()).unwrap();
+        Bm25::default().rank(&query, &index, top_k)
+    }
+
+    #[test]
+    fn negation_only_queries_score_positive_zero() {
+        let ranked = rank(&["fast code", "slow code", "safe code"], "NOT fast", 10);
+        let ids: Vec<u32> = ranked.iter().map(|doc| doc.document_id).collect();
+        assert_eq!(ids, [2, 3]);
+        for doc in ranked {
+            assert_eq!(doc.score.to_bits(), 0.0f32.to_bits(), "{:?}", doc);
+        }
+    }
+
+    #[test]
+    fn more_occurrences_rank_higher_and_ties_go_by_id() {
+        let texts = ["rust", "rust rust rust", "go", "rust"];
+        let ids: Vec<u32> = rank(&texts, "rust", 10)
+            .iter()
+            .map(|doc| doc.document_id)
+            .collect();
+        assert_eq!(ids, [2, 1, 4]);
+        let top: Vec<u32> = rank(&texts, "rust", 2)
+            .iter()
+            .map(|doc| doc.document_id)
+            .collect();
+        assert_eq!(top, [2, 1]);
+    }
+}
@@ -0,0 +1,308 @@
+//! Searching an index file on disk.
+//!
+//! `InMemoryIndex::from_index_file` decodes every term of an index file before
+//! it can answer a single query. `IndexSearcher` instead maps the file into
+//! memory with `MmapIndexReader` and only decodes the term index, a small
+//! sample of the table of entries. To look up a term, it binary searches the
+//! term index, scans the one block of the table of entries that may contain
+//! the term, and then decodes the postings for that term directly. The cost
+//! of a query depends on the number of postings it touches, not on the size
+//! of the index.
+
+use std::{collections::HashMap, io::Cursor, path::Path};
+
+use crate::{
+    checksum::checksum,
+    codec::PostingList,
+    error::{IndexError, Result},
+    index::{find_posting, Document, Posting},
+    mmap::{MmapIndexReader, TermIndexSample},
+    query::{DocSet, Query},
+    read::{read_document, DocRecord, Entry, StoredTextRef},
+    results::{match_positions, SearchHit, SearchResults},
+    score::{Bm25, CorpusStats, ScoredDoc},
+    store,
+    write::FLAG_STORED_TEXT,
+};
+
+/// Answers queries against an index file without loading it into memory.
+///
+/// All methods take `&self` and only read the memory-mapped file, so a single
+/// searcher can be shared between threads.
+pub struct IndexSearcher {
+    reader: MmapIndexReader,
+
+    /// Every `TERM_INDEX_INTERVAL`th term of the table of entries, in
+    /// increasing order of term.
+    term_index: Vec<TermIndexSample>,
+}
+
+impl IndexSearcher {
+    /// Open an index file for searching. Only the header and the term index
+    /// are decoded and checked. The other parts of the file are checked as
+    /// they are read: the block of the table of entries a lookup scans, the
+    /// records of the table of documents it reads, and the data they point
+    /// to. Use `verify_index_file` to check the whole file.
+    pub fn open<P: AsRef<Path>>(filename: P) -> Result<IndexSearcher> {
+        let reader = MmapIndexReader::open(filename)?;
+        let term_index = reader.term_index_samples()?;
+        Ok(IndexSearcher { reader, term_index })
+    }
+
+    /// Name of the analyzer that produced the terms in the index.
+    pub fn analyzer(&self) -> &str {
+        self.reader.analyzer()
+    }
+
+    /// True if the index stores the text of its documents, so that
+    /// `stored_text` returns it.
+    pub fn has_stored_text(&self) -> bool {
+        self.reader.flags() & FLAG_STORED_TEXT != 0
+    }
+
+    /// Look up the entry for `term` in the table of entries.
+    pub fn entry(&self, term: &str) -> Result<Option<Entry>> {
+        // The last sampled term that is not greater than `term` starts the
+        // only block that can contain it.
+        let block = self
+            .term_index
+            .partition_point(|sample| sample.term.as_str() <= term);
+        if block == 0 {
+            return Ok(None);
+        }
+        for entry in self.reader.entries_block(&self.term_index, block - 1)? {
+            let entry = entry?;
+            if entry.term == term {
+                return Ok(Some(entry.to_entry()));
+            }
+            if entry.term > term {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// The postings for the term described by `entry`, decoded lazily.
+    pub fn posting_list(&self, entry: &Entry) -> Result<PostingList<'_>> {
+        let data = self.reader.postings(entry.offset, entry.nbytes)?;
+        if checksum(data) != entry.crc {
+            return Err(IndexError::checksum_mismatch(
+                format!("hits of term {:?}", entry.term),
+                entry.offset,
+            ));
+        }
+        Ok(PostingList::new(data, entry.offset))
+    }
+
+    /// Read all postings for the term described by `entry`.
+    pub fn postings(&self, entry: &Entry) -> Result<Vec<Posting>> {
+        self.posting_list(entry)?.collect()
+    }
+
+    /// Number of documents in the index.
+    pub fn doc_count(&self) -> u32 {
+        self.reader.doc_count() as u32
+    }
+
+    /// Look up a document by id, binary searching the table of documents.
+    /// Its stored text is not read: `stored` is always `None`; see
+    /// `stored_text`.
+    pub fn document(&self, document_id: u32) -> Result<Option<Document>> {
+        match self.doc_record(document_id)? {
+            Some(record) => Ok(Some(self.read_document(&record)?.0)),
+            None => Ok(None),
+        }
+    }
+
+    /// The text of a document as it was indexed, if the index stores it.
+    pub fn stored_text(&self, document_id: u32) -> Result<Option<String>> {
+        let record = self
+            .doc_record(document_id)?
+            .ok_or(IndexError::DocumentMissing { id: document_id })?;
+        let Some(text) = self.read_document(&record)?.1 else {
+            return Ok(None);
+        };
+        let data = self.reader.stored_text_data(&text)?;
+        if checksum(data) != text.crc {
+            return Err(IndexError::checksum_mismatch(
+                format!("stored text of document {}", document_id),
+                text.offset,
+            ));
+        }
+        store::decompress(data)
+            .map(Some)
+            .map_err(|_| IndexError::CorruptStoredText { id: document_id })
+    }
+
+    /// Binary search the table of documents for the record of a document.
+    fn doc_record(&self, document_id: u32) -> Result<Option<DocRecord>> {
+        let (mut lo, mut hi) = (0, self.reader.doc_count());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let record = self.reader.doc_table_record(mid)?;
+            if record.id == document_id {
+                return Ok(Some(record));
+            }
+            if record.id < document_id {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(None)
+    }
+
+    /// All documents in the index, in increasing order of id, without their
+    /// stored text.
+    pub fn documents(&self) -> Result<Vec<Document>> {
+        (0..self.reader.doc_count())
+            .map(|i| Ok(self.read_document(&self.reader.doc_table_record(i)?)?.0))
+            .collect()
+    }
+
+    fn read_document(&self, record: &DocRecord) -> Result<(Document, Option<StoredTextRef>)> {
+        let data = self.reader.document_data(record)?;
+        if checksum(data) != record.crc {
+            return Err(IndexError::checksum_mismatch(
+                format!("document {}", record.id),
+                record.offset,
+            ));
+        }
+        Ok(read_document(&mut Cursor::new(data), self.reader.flags())?)
+    }
+
+    /// The ids of all documents in the index, in increasing order.
+    pub fn document_ids(&self) -> Result<Vec<u32>> {
+        (0..self.reader.doc_count())
+            .map(|i| self.reader.doc_table_record(i).map(|record| record.id))
+            .collect()
+    }
+
+    /// Find the documents that match `query` and return the `top_k` best
+    /// ones according to `ranking`, with where the terms of the query appear
+    /// in each.
+    ///
+    /// The query must have been parsed with the analyzer the index was built
+    /// with (see `analyzer`).
+    pub fn search(&self, query: &Query, ranking: &Bm25, top_k: usize) -> Result<SearchResults> {
+        let mut loaded = LoadedQuery::load(self, query)?;
+
+        // BM25 needs the length of every matching document, which its record
+        // in the table of documents has: every match costs a lookup in the
+        // table, but only the data of the top `top_k` is read.
+        let matches = query.matching_docs(&loaded);
+        for &document_id in &matches {
+            let record = self
+                .doc_record(document_id)?
+                .ok_or(IndexError::DocumentMissing { id: document_id })?;
+            loaded.doc_lengths.insert(document_id, record.length);
+        }
+
+        let terms = query.positive_terms();
+        let hits = ranking
+            .rank_matches(query, &loaded, matches, top_k)
+            .into_iter()
+            .map(|ScoredDoc { document_id, score }| {
+                let doc = self
+                    .document(document_id)?
+                    .ok_or(IndexError::DocumentMissing { id: document_id })?;
+                Ok(SearchHit {
+                    doc_id: document_id,
+                    path: doc.path,
+                    score,
+                    matches: match_positions(
+                        terms
+                            .iter()
+                            .filter_map(|term| loaded.posting(term, document_id)),
+                    ),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(SearchResults { hits })
+    }
+}
+
+/// The part of an index needed to answer one query: the postings of the
+/// terms in the query, and the documents that match it.
+struct LoadedQuery {
+    /// For every term of the query, its `df` and postings.
+    terms: HashMap<String, (u32, Vec<Posting>)>,
+    /// The ids of all documents, only loaded if the query needs them; see
+    /// `Query::needs_all_docs`.
+    all_docs: Vec<u32>,
+    /// The length of every document that matches the query.
+    doc_lengths: HashMap<u32, u32>,
+    doc_count: u32,
+    word_count: u64,
+}
+
+impl LoadedQuery {
+    fn load(searcher: &IndexSearcher, query: &Query) -> Result<LoadedQuery> {
+        let mut terms = HashMap::new();
+        for term in query.terms() {
+            if terms.contains_key(term) {
+                continue;
+            }
+            if let Some(entry) = searcher.entry(term)? {
+                terms.insert(term.to_string(), (entry.df, searcher.postings(&entry)?));
+            }
+        }
+        let all_docs = if query.needs_all_docs() {
+            searcher.document_ids()?
+        } else {
+            vec![]
+        };
+        Ok(LoadedQuery {
+            terms,
+            all_docs,
+            doc_lengths: HashMap::new(),
+            doc_count: searcher.doc_count(),
+            word_count: searcher.reader.word_count(),
+        })
+    }
+
+    /// The `Posting` for `term` in the given document, if the document
+    /// contains the term.
+    fn posting(&self, term: &str, document_id: u32) -> Option<&Posting> {
+        let (_, postings) = self.terms.get(term)?;
+        find_posting(postings, document_id)
+    }
+}
+
+impl DocSet for LoadedQuery {
+    fn term_docs(&self, term: &str) -> Vec<u32> {
+        match self.terms.get(term) {
+            Some((_, postings)) => postings.iter().map(|posting| posting.doc_id).collect(),
+            None => vec![],
+        }
+    }
+
+    fn term_positions(&self, term: &str, document_id: u32) -> Vec<u32> {
+        match self.posting(term, document_id) {
+            Some(posting) => posting.positions.iter().map(|pos| pos.position).collect(),
+            None => vec![],
+        }
+    }
+
+    fn all_docs(&self) -> Vec<u32> {
+        self.all_docs.clone()
+    }
+}
+
+impl CorpusStats for LoadedQuery {
+    fn doc_count(&self) -> u32 {
+        self.doc_count
+    }
+
+    fn total_doc_length(&self) -> u64 {
+        self.word_count
+    }
+
+    fn doc_length(&self, document_id: u32) -> u32 {
+        self.doc_lengths.get(&document_id).copied().unwrap_or(0)
+    }
+
+    fn doc_freq(&self, term: &str) -> u32 {
+        self.terms.get(term).map_or(0, |(df, _)| *df)
+    }
+}
@@ -0,0 +1,207 @@
+//! Keyword-in-context snippets.
+//!
+//! Showing a whole document for every search result doesn't work for long
+//! documents. A snippet is a window of the document of a few words around
+//! one or more matches. The windows around all matches are computed from the
+//! token ordinals stored with every hit, windows that overlap or touch are
+//! merged, and the windows with the most matches are returned, in document
+//! order.
+//!
+//! Words are counted the way the index counts them: the document is broken
+//! into tokens by the analyzer the index was built with, so the `context`
+//! words on each side of a match are the words `NEAR/k` counts between its
+//! terms.
+
+use crate::{analysis::Analyzer, index::TokenPos};
+
+/// A window of a document around one or more matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snippet {
+    /// Byte offset of the first byte of the snippet in the document text.
+    pub start: usize,
+
+    /// Byte offset just past the last byte of the snippet.
+    pub end: usize,
+
+    /// The matches inside the snippet, in increasing order of `start_pos`.
+    pub matches: Vec<TokenPos>,
+
+    /// True if the document has words before the snippet.
+    pub truncated_start: bool,
+
+    /// True if the document has words after the snippet.
+    pub truncated_end: bool,
+}
+
+impl Snippet {
+    /// The text of the snippet, given the text of the document it was
+    /// extracted from.
+    pub fn text<'a>(&self, document: &'a str) -> &'a str {
+        &document[self.start..self.end]
+    }
+}
+
+/// Extract at most `count` snippets of `text` around `matches`, with up to
+/// `context` words on each side of every match.
+///
+/// `matches` are the positions of a `SearchHit`, and `text` must be broken
+/// into tokens by `analyzer` the same way it was when it was indexed.
+/// Snippets are returned in document order. If `count` is smaller than the
+/// number of windows, the windows with the most matches are kept, and the
+/// earliest ones among equals.
+pub fn snippets(
+    text: &str,
+    analyzer: &Analyzer,
+    matches: &[TokenPos],
+    count: usize,
+    context: u32,
+) -> Vec<Snippet> {
+    let tokens = analyzer.analyze(text);
+    let (Some(first), Some(last)) = (tokens.first(), tokens.last()) else {
+        return vec![];
+    };
+    let (first_position, last_position) = (first.position, last.position);
+
+    let mut matches = matches.to_vec();
+    matches.sort_by_key(|pos| pos.position);
+
+    // Windows of token ordinals, with the matches in each.
+    let mut windows: Vec<(u32, u32, Vec<TokenPos>)> = vec![];
+    for pos in matches {
+        let from = pos.position.saturating_sub(context).max(first_position);
+        let to = pos
+            .position
+            .saturating_add(context)
+            .min(last_position)
+            .max(pos.position);
+        match windows.last_mut() {
+            Some((_, last_to, window)) if from <= last_to.saturating_add(1) => {
+                *last_to = (*last_to).max(to);
+                window.push(pos);
+            }
+            _ => windows.push((from, to, vec![pos])),
+        }
+    }
+
+    // Keep the `count` windows with the most matches, then restore document
+    // order.
+    let mut best: Vec<usize> = (0..windows.len()).collect();
+    best.sort_by_key(|&i| std::cmp::Reverse(windows[i].2.len()));
+    best.truncate(count);
+    best.sort();
+
+    best.into_iter()
+        .filter_map(|i| {
+            let (from, to, mut matches) = windows[i].clone();
+            // Filters may have dropped tokens, so the ends of the window are
+            // the first and last tokens that remain in it.
+            let lo = tokens.partition_point(|token| token.position < from);
+            let hi = tokens.partition_point(|token| token.position <= to);
+            let (start_token, end_token) = (tokens.get(lo)?, tokens.get(hi.checked_sub(1)?)?);
+            let (truncated_start, truncated_end) = (lo > 0, hi < tokens.len());
+            // A snippet that reaches the first or last word of the document
+            // also gets the punctuation around it.
+            let start = if truncated_start {
+                start_token.start.min(matches[0].start_pos as usize)
+            } else {
+                0
+            };
+            let end = if truncated_end {
+                end_token
+                    .end
+                    .max(matches[matches.len() - 1].end_pos as usize)
+            } else {
+                text.len()
+            };
+            if end > text.len() || !text.is_char_boundary(start) || !text.is_char_boundary(end) {
+                return None;
+            }
+            matches.sort_by_key(|pos| pos.start_pos);
+            Some(Snippet {
+                start,
+                end,
+                matches,
+                truncated_start,
+                truncated_end,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every occurrence of `term` in `text`, as a search would find it.
+    fn matches(text: &str, term: &str) -> Vec<TokenPos> {
+        Analyzer::standard()
+            .analyze(text)
+            .into_iter()
+            .filter(|token| token.text == term)
+            .map(|token| TokenPos {
+                start_pos: token.start as u32,
+                end_pos: token.end as u32,
+                position: token.position,
+            })
+            .collect()
+    }
+
+    /// The text of every snippet, with an ellipsis where it is truncated.
+    fn shown(text: &str, count: usize, context: u32) -> Vec<String> {
+        snippets(
+            text,
+            &Analyzer::standard(),
+            &matches(text, "x"),
+            count,
+            context,
+        )
+        .iter()
+        .map(|snippet| {
+            format!(
+                "{}{}{}",
+                if snippet.truncated_start { "..." } else { "" },
+                snippet.text(text),
+                if snippet.truncated_end { "..." } else { "" }
+            )
+        })
+        .collect()
+    }
+
+    #[test]
+    fn overlapping_and_touching_windows_merge() {
+        let text = "x one two x three four five six seven x";
+        assert_eq!(shown(text, 5, 1), ["x one two x three...", "...seven x"]);
+        // The windows of the first two matches overlap.
+        assert_eq!(
+            shown(text, 5, 2),
+            ["x one two x three four...", "...six seven x"]
+        );
+        assert_eq!(shown(text, 5, 3), [text]);
+        let merged = snippets(text, &Analyzer::standard(), &matches(text, "x"), 5, 1);
+        assert_eq!(merged[0].matches, matches(text, "x")[..2]);
+        assert_eq!(merged[1].matches, matches(text, "x")[2..]);
+    }
+
+    #[test]
+    fn windows_with_the_most_matches_are_kept() {
+        let text = "x a b c d e x x f g h i j x";
+        assert_eq!(shown(text, 1, 0), ["...x x..."]);
+        // Among windows with as many matches, the earliest, in document order.
+        assert_eq!(shown(text, 2, 0), ["x...", "...x x..."]);
+        assert_eq!(shown(text, 3, 0), ["x...", "...x x...", "...x"]);
+        assert!(shown(text, 0, 0).is_empty());
+    }
+
+    #[test]
+    fn snippets_at_the_edges_are_not_truncated() {
+        // Reaching the first or last word takes in the punctuation around it.
+        let text = "(x one two three four five six seven x.)";
+        assert_eq!(shown(text, 2, 1), ["(x one...", "...seven x.)"]);
+        assert_eq!(shown("  x  ", 1, 5), ["  x  "]);
+        let text = "one two x three four";
+        assert_eq!(shown(text, 1, 1), ["...two x three..."]);
+        assert_eq!(shown(text, 1, 2), [text]);
+        assert!(shown("one two three", 1, 2).is_empty());
+        assert!(shown("", 1, 2).is_empty());
+    }
+}
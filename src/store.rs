@@ -0,0 +1,29 @@
+//! Stored document text.
+//!
+//! Showing a search result means reading the document again: to highlight
+//! it, or to extract snippets. Reading it from its original path breaks as
+//! soon as the file is moved, edited or deleted, and an edited file no
+//! longer matches the offsets in the index. An index can instead store the
+//! text of every document as it was indexed, compressed with DEFLATE, in a
+//! document store section of the file (see `FLAG_STORED_TEXT`). Searches
+//! then show the stored copy, and tell from the fingerprint of the document
+//! (see `fingerprint`) whether the file on disk still matches it.
+
+use std::io::{self, Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+/// Compress the text of a document for storing in an index file.
+pub fn compress(text: &str) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+    // Writing to a `Vec` can't fail.
+    encoder.write_all(text.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Decompress text compressed by `compress`.
+pub fn decompress(data: &[u8]) -> io::Result<String> {
+    let mut text = String::new();
+    DeflateDecoder::new(data).read_to_string(&mut text)?;
+    Ok(text)
+}
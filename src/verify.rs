@@ -0,0 +1,232 @@
+//! Checking an index file for damage.
+//!
+//! Readers check checksums as they go and stop at the first mismatch.
+//! `verify_index_file` instead walks the whole file and reports every damaged
+//! section, term and document it finds.
+
+use std::{fmt, path::Path};
+
+use crate::{
+    checksum::checksum,
+    codec::PostingList,
+    error::Result,
+    mmap::{EntryRef, MmapIndexReader},
+    read::read_document,
+    store,
+};
+
+/// A damaged part of an index file.
+#[derive(Debug)]
+pub enum Damage {
+    /// The checksum of a section does not match the one in the header.
+    Section(&'static str),
+    /// The table of entries cannot be decoded past the entry for `after`
+    /// (or past its start, if `after` is `None`).
+    EntryTable {
+        after: Option<String>,
+        error: String,
+    },
+    /// The term index cannot be decoded.
+    TermIndex { error: String },
+    /// The block of the table of entries that starts at the sampled entry for
+    /// `term` is out of bounds or does not match its checksum in the term
+    /// index.
+    EntryBlock { term: String },
+    /// The header and the table of entries disagree on the number of terms.
+    TermCount { header: u32, table: u32 },
+    /// The hits of a term are out of bounds, do not match their checksum, or
+    /// do not decode to the postings described by the entry.
+    Term {
+        term: String,
+        offset: u64,
+        nbytes: u64,
+    },
+    /// A record of the table of documents cannot be read.
+    DocRecord { index: u64, error: String },
+    /// The data of a document is out of bounds, does not match its checksum,
+    /// or does not match its record.
+    Document { id: u32, offset: u64, nbytes: u64 },
+    /// The stored text of a document is out of bounds, does not match its
+    /// checksum, or does not decompress.
+    StoredText { id: u32, offset: u64, nbytes: u64 },
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Damage::Section(name) => write!(f, "{}: checksum mismatch", name),
+            Damage::EntryTable { after: None, error } => {
+                write!(f, "table of entries: first entry is unreadable: {}", error)
+            }
+            Damage::EntryTable {
+                after: Some(term),
+                error,
+            } => write!(
+                f,
+                "table of entries: entry after term {:?} is unreadable: {}",
+                term, error
+            ),
+            Damage::TermIndex { error } => write!(f, "term index is unreadable: {}", error),
+            Damage::EntryBlock { term } => write!(
+                f,
+                "table of entries: block starting at term {:?} is damaged",
+                term
+            ),
+            Damage::TermCount { header, table } => write!(
+                f,
+                "table of entries: header says {} terms, table has {}",
+                header, table
+            ),
+            Damage::Term {
+                term,
+                offset,
+                nbytes,
+            } => write!(
+                f,
+                "term {:?}: hits at offset {} ({} bytes) are damaged",
+                term, offset, nbytes
+            ),
+            Damage::DocRecord { index, error } => {
+                write!(
+                    f,
+                    "table of documents: record {} is unreadable: {}",
+                    index, error
+                )
+            }
+            Damage::Document { id, offset, nbytes } => write!(
+                f,
+                "document {}: data at offset {} ({} bytes) is damaged",
+                id, offset, nbytes
+            ),
+            Damage::StoredText { id, offset, nbytes } => write!(
+                f,
+                "document {}: stored text at offset {} ({} bytes) is damaged",
+                id, offset, nbytes
+            ),
+        }
+    }
+}
+
+/// Check every checksum of an index file, and report what is damaged.
+///
+/// Returns an error if the file cannot be opened or its header is damaged,
+/// since nothing else in the file can be located without it.
+pub fn verify_index_file<P: AsRef<Path>>(filename: P) -> Result<Vec<Damage>> {
+    let reader = MmapIndexReader::open(filename)?;
+    let mut damage: Vec<Damage> = reader
+        .damaged_sections()
+        .into_iter()
+        .map(|(name, _)| Damage::Section(name))
+        .collect();
+
+    // Blocks of the table of entries can only be located through an intact
+    // term index.
+    let term_index_damaged = damage
+        .iter()
+        .any(|d| matches!(d, Damage::Section("term index")));
+    if !term_index_damaged {
+        match reader.term_index_samples() {
+            Ok(samples) => {
+                for (i, sample) in samples.iter().enumerate() {
+                    if reader.entries_block(&samples, i).is_err() {
+                        damage.push(Damage::EntryBlock {
+                            term: sample.term.clone(),
+                        });
+                    }
+                }
+            }
+            Err(err) => damage.push(Damage::TermIndex {
+                error: err.to_string(),
+            }),
+        }
+    }
+
+    let mut last_term: Option<String> = None;
+    let mut term_count = 0;
+    for entry in reader.entries_from(0) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                damage.push(Damage::EntryTable {
+                    after: last_term.take(),
+                    error: err.to_string(),
+                });
+                break;
+            }
+        };
+        term_count += 1;
+        let intact = reader
+            .postings(entry.offset, entry.nbytes)
+            .is_ok_and(|data| checksum(data) == entry.crc && postings_match(data, &entry));
+        if !intact {
+            damage.push(Damage::Term {
+                term: entry.term.to_string(),
+                offset: entry.offset,
+                nbytes: entry.nbytes,
+            });
+        }
+        last_term = Some(entry.term.to_string());
+    }
+    if term_count != reader.term_count() {
+        damage.push(Damage::TermCount {
+            header: reader.term_count(),
+            table: term_count,
+        });
+    }
+
+    for i in 0..reader.doc_count() {
+        let record = match reader.doc_table_record(i) {
+            Ok(record) => record,
+            Err(err) => {
+                damage.push(Damage::DocRecord {
+                    index: i,
+                    error: err.to_string(),
+                });
+                continue;
+            }
+        };
+        let doc = reader
+            .document_data(&record)
+            .ok()
+            .filter(|data| checksum(data) == record.crc)
+            .and_then(|data| read_document(&mut &data[..], reader.flags()).ok())
+            .filter(|(doc, _)| doc.id == record.id && doc.length == record.length);
+        let Some((_, text)) = doc else {
+            damage.push(Damage::Document {
+                id: record.id,
+                offset: record.offset,
+                nbytes: record.nbytes,
+            });
+            continue;
+        };
+        if let Some(text) = text {
+            let intact = reader
+                .stored_text_data(&text)
+                .is_ok_and(|data| checksum(data) == text.crc && store::decompress(data).is_ok());
+            if !intact {
+                damage.push(Damage::StoredText {
+                    id: record.id,
+                    offset: text.offset,
+                    nbytes: text.nbytes,
+                });
+            }
+        }
+    }
+
+    Ok(damage)
+}
+
+/// True if `data` decodes to `entry.df` postings, the last one for
+/// `entry.last_doc`.
+fn postings_match(data: &[u8], entry: &EntryRef) -> bool {
+    let mut df = 0;
+    let mut last_doc = None;
+    for posting in PostingList::new(data, entry.offset) {
+        match posting {
+            Ok(posting) => last_doc = Some(posting.doc_id),
+            Err(_) => return false,
+        }
+        df += 1;
+    }
+    df == entry.df && last_doc == Some(entry.last_doc)
+}
@@ -1,18 +1,91 @@
+//! Writing index files.
+//!
+//! `IndexFileWriter` writes an index file in a single pass, and then goes
+//! back to fill in the header. All integers are little-endian. A file of
+//! format version `FORMAT_VERSION` is laid out as follows:
+//!
+//! - Header: `MAGIC`, the format version (u32), flags (u32; see
+//!   `SUPPORTED_FLAGS`), the offsets of the table of entries, the table of
+//!   documents and the term index (u64 each), the number of documents and of
+//!   entries (u32 each), the total number of tokens in all documents (u64),
+//!   the checksums of those three sections (u32 each), the name of the
+//!   analyzer (u32 length followed by bytes), and the checksum of the header
+//!   itself (u32).
+//! - Hits: the postings of every term as a compressed posting list (see
+//!   `codec`), in the order of the table of entries.
+//! - Document store, if the file stores text (see `FLAG_STORED_TEXT`): the
+//!   compressed text of every document (see `store`).
+//! - Document data, for every document: its id (u32), its length in tokens
+//!   (u32), the fingerprint of its file (size, modification time and content
+//!   hash, u64 each), its path (u64 length followed by bytes), and, if the
+//!   file stores text, the offset (u64), length (u64, 0 if its text is not
+//!   stored) and checksum (u32) of its text in the document store.
+//! - Table of entries, sorted by term. Every entry is the offset (u64) and
+//!   length (u64) of the hits of its term, its `df` (u32), the checksum of
+//!   the hits (u32), the id of the last document in them (u32), and the term
+//!   (u32 length followed by bytes).
+//! - Table of documents, sorted by document id. Every record is
+//!   `DOC_TABLE_RECORD_SIZE` bytes: the document id (u32), its length in
+//!   tokens (u32), the offset (u64), length (u64) and checksum (u32) of its
+//!   data, and the checksum of the record itself (u32).
+//! - Term index: every `TERM_INDEX_INTERVAL`th entry of the table of
+//!   entries, as the position of the entry relative to the start of the table
+//!   (u64), the checksum of the block of entries from there up to the next
+//!   sample (u32), and the term (u32 length followed by bytes).
+//!
+//! Every part is covered by a checksum (see `checksum`), so that readers only
+//! check what they read: the hits of a term against its entry, the data of a
+//! document against its record, its stored text against its data, a block of
+//! entries against the term index, and a record of the table of documents
+//! against itself. The header has the checksum of each table as a whole.
+
 use std::{
     fs::File,
-    io::{self, BufWriter, Seek, SeekFrom, Write},
+    io::{BufWriter, Seek, SeekFrom, Write},
     os::unix::ffi::OsStrExt,
     path::PathBuf,
 };
 
 use byteorder::{LittleEndian, WriteBytesExt};
+use tracing::debug;
 
 use crate::{
-    index::{Document, InMemoryIndex},
+    checksum::{checksum, extend_checksum},
+    codec::{encode_posting, rebase_posting_list},
+    error::Result,
+    index::{Document, InMemoryIndex, Posting},
+    read::{DocRecord, IndexHeader},
     tmp::TmpDir,
 };
 
-/// A structure to manage writing to an index file efficiently.
+/// The first bytes of every index file.
+pub const MAGIC: [u8; 4] = *b"IIDX";
+
+/// Version of the index file format written by `IndexFileWriter`; see the
+/// module documentation. Readers reject files with any other version. Bump
+/// this whenever the layout of the file changes, or the meaning of what it
+/// stores. Version 1 is the original format, which had no header.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Flag set in the header of files that store the text of their documents,
+/// compressed (see `store`), in a document store section.
+pub const FLAG_STORED_TEXT: u32 = 1;
+
+/// Flags for optional features of an index file that this build understands.
+/// Readers reject files with any other flag set.
+pub const SUPPORTED_FLAGS: u32 = FLAG_STORED_TEXT;
+
+/// Every `TERM_INDEX_INTERVAL`th entry of the table of entries is copied into
+/// the term index at the end of the file, so that a reader can find the
+/// entry for a term by scanning a single block of the table.
+pub const TERM_INDEX_INTERVAL: u32 = 64;
+
+/// Size of a record in the table of documents. Records have a fixed size so
+/// that a reader can binary search the table.
+pub const DOC_TABLE_RECORD_SIZE: u64 = 4 + 4 + 8 + 8 + 4 + 4;
+
+/// A structure to manage writing to an index file efficiently, laid out as
+/// described in the module documentation.
 pub struct IndexFileWriter {
     /// Tracks the current write position in the file.
     offset: u64,
@@ -20,53 +93,172 @@ pub struct IndexFileWriter {
     writer: BufWriter<File>,
     /// Buffer to store contents entries before they are written.
     contents_buf: Vec<u8>,
+    /// Number of entries written to `contents_buf`.
+    entry_count: u32,
+    /// Checksum of the postings written since the last entry.
+    hits_crc: u32,
+    /// Document id of the last posting written since the last entry, if any.
+    last_doc: Option<u32>,
+    /// The position in `contents_buf` and the term of every entry sampled
+    /// for the term index.
+    term_index: Vec<(u64, String)>,
+    /// The data of every document written so far. It follows the document
+    /// store, which is written as documents come, so it is kept until
+    /// `finish`.
+    docs_buf: Vec<u8>,
+    /// The record of every document written so far. Offsets are relative to
+    /// the start of `docs_buf` until `finish`.
+    doc_table: Vec<DocRecord>,
+    /// Total number of tokens in the documents written so far.
+    word_count: u64,
+    /// Name of the analyzer that produced the terms.
+    analyzer: String,
+    /// Optional features of the file; see `SUPPORTED_FLAGS`.
+    flags: u32,
 }
 
 impl IndexFileWriter {
     /// Constructs a new `IndexFileWriter`.
     ///
     /// Initializes the file with a header size and sets the initial offset.
-    /// The header will store the final size of the main data section of the file.
+    /// The header will identify the file format and store the offsets of the
+    /// sections that follow the main data, some counts, and the name of the
+    /// analyzer that produced the terms.
     ///
     /// # Arguments
     /// * `f` - A buffered writer pre-configured to write to the target file
+    /// * `analyzer` - Name of the `Analyzer` used to build the index
     ///
     /// # Errors
     /// Returns an error if writing the initial header fails.
-    pub fn new(mut f: BufWriter<File>) -> io::Result<IndexFileWriter> {
-        const HEADER_SIZE: u64 = 8;
-        f.write_u64::<LittleEndian>(0)?; // content start
-        Ok(IndexFileWriter {
-            offset: HEADER_SIZE,
+    pub fn new(f: BufWriter<File>, analyzer: &str) -> Result<IndexFileWriter> {
+        let mut writer = IndexFileWriter {
+            offset: 0,
             writer: f,
             contents_buf: vec![],
-        })
+            entry_count: 0,
+            hits_crc: 0,
+            last_doc: None,
+            term_index: vec![],
+            docs_buf: vec![],
+            doc_table: vec![],
+            word_count: 0,
+            analyzer: analyzer.to_string(),
+            flags: 0,
+        };
+        // Written again by `finish`, once the offsets and checksums are known.
+        let header = writer.header();
+        header.write_to(&mut writer.writer)?;
+        writer.offset = header.size();
+        Ok(writer)
+    }
+
+    /// The header of the file, without the offsets and checksums of the
+    /// sections that follow the main data.
+    fn header(&self) -> IndexHeader {
+        IndexHeader {
+            version: FORMAT_VERSION,
+            flags: self.flags,
+            entries_offset: 0,
+            docs_offset: 0,
+            term_index_offset: 0,
+            doc_count: self.doc_table.len() as u32,
+            term_count: self.entry_count,
+            word_count: self.word_count,
+            entries_crc: 0,
+            docs_crc: 0,
+            term_index_crc: 0,
+            analyzer: self.analyzer.clone(),
+        }
+    }
+
+    /// Store the text of documents in the file; see `FLAG_STORED_TEXT`.
+    ///
+    /// # Panics
+    /// Panics if some documents have already been written.
+    pub fn store_text(&mut self) {
+        assert!(self.doc_table.is_empty(), "text stored after documents");
+        self.flags |= FLAG_STORED_TEXT;
+    }
+
+    /// The current write position in the file.
+    pub fn offset(&self) -> u64 {
+        self.offset
     }
 
     /// Writes a buffer to the file and updates the offset.
+    fn write_main(&mut self, buf: &[u8]) -> Result<()> {
+        self.writer.write_all(buf)?;
+        self.hits_crc = extend_checksum(self.hits_crc, buf);
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Appends postings to the postings of the current term.
+    ///
+    /// The postings of a term can be written in several pieces, as long as
+    /// every piece only has documents that come after those already written.
     ///
     /// # Arguments
-    /// * `buf` - The data to write to the file
+    /// * `postings` - The postings to write, sorted by document id
     ///
     /// # Errors
-    /// Returns an error if the write operation fails.
-    pub fn write_main(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.writer.write_all(buf)?;
-        self.offset += buf.len() as u64;
+    /// Returns an error if the write operation fails, or if the postings are
+    /// not sorted by document id.
+    pub fn write_postings(&mut self, postings: &[Posting]) -> Result<()> {
+        let mut buf = vec![];
+        for posting in postings {
+            encode_posting(&mut buf, self.last_doc, posting)?;
+            self.last_doc = Some(posting.doc_id);
+        }
+        self.write_main(&buf)
+    }
+
+    /// Appends a posting list, as stored in another index file, to the
+    /// postings of the current term, without decoding it.
+    ///
+    /// # Arguments
+    /// * `data` - The encoded posting list
+    /// * `offset` - The offset of `data` in the file it comes from
+    /// * `last_doc` - The document id of the last posting in `data`
+    pub(crate) fn copy_postings(&mut self, data: &[u8], offset: u64, last_doc: u32) -> Result<()> {
+        let (head, rest) = rebase_posting_list(data, offset, self.last_doc)?;
+        self.write_main(&head)?;
+        self.write_main(rest)?;
+        self.last_doc = Some(last_doc);
         Ok(())
     }
 
     /// Appends a content entry to the internal buffer.
     ///
+    /// Entries must be written in increasing order of `term`, each one right
+    /// after the postings it describes, since the checksum and the last
+    /// document id of the entry are those of everything written with
+    /// `write_postings` and `copy_postings` since the previous entry.
+    ///
     /// # Arguments
     /// * `term` - The term associated with the entry
     /// * `df` - Document frequency for the term
     /// * `offset` - Offset where the term data starts in the file
     /// * `nbytes` - Number of bytes of the term data
     pub fn write_contents_entry(&mut self, term: String, df: u32, offset: u64, nbytes: u64) {
+        if self.entry_count.is_multiple_of(TERM_INDEX_INTERVAL) {
+            self.term_index
+                .push((self.contents_buf.len() as u64, term.clone()));
+        }
+        self.entry_count += 1;
+
         self.contents_buf.write_u64::<LittleEndian>(offset).unwrap();
         self.contents_buf.write_u64::<LittleEndian>(nbytes).unwrap();
         self.contents_buf.write_u32::<LittleEndian>(df).unwrap();
+        self.contents_buf
+            .write_u32::<LittleEndian>(self.hits_crc)
+            .unwrap();
+        self.contents_buf
+            .write_u32::<LittleEndian>(self.last_doc.unwrap_or(0))
+            .unwrap();
+        self.hits_crc = 0;
+        self.last_doc = None;
         let bytes = term.bytes();
         self.contents_buf
             .write_u32::<LittleEndian>(bytes.len() as u32)
@@ -76,38 +268,118 @@ impl IndexFileWriter {
 
     /// Writes a `Document` object to the file associated with the `IndexFileWriter`.
     ///
-    /// This function serializes a `Document` object and writes it to the underlying `BufWriter<File>`.
-    /// The serialization format is as follows:
-    /// - Document ID (u32)
-    /// - Path length (u64) followed by Path bytes (variable length)
+    /// If the file stores text (see `store_text`), the compressed text in
+    /// `doc.stored` is appended to the document store. The data of the
+    /// document is kept until `finish`, since it follows the store.
     ///
-    /// The offsets are updated accordingly after each write to ensure the correct position
-    /// for subsequent writes.
-    pub fn write_document(&mut self, doc: &Document) -> io::Result<()> {
-        self.writer.write_u32::<LittleEndian>(doc.id)?;
-        self.writer
-            .write_u64::<LittleEndian>(doc.path.as_os_str().len() as u64)?;
-        self.writer.write_all(doc.path.as_os_str().as_bytes())?;
-        self.offset += 4 + 8 + doc.path.as_os_str().len() as u64;
+    /// Documents must be written after the data of every term. The offsets are
+    /// updated accordingly after each write, and the document is added to the
+    /// table of documents.
+    pub fn write_document(&mut self, doc: &Document) -> Result<()> {
+        self.write_document_with_text(doc, doc.stored.as_deref())
+    }
+
+    /// Like `write_document`, with `text` as the compressed text of the
+    /// document instead of `doc.stored`. Merging uses this to copy stored
+    /// text from one file to another without loading every document's text.
+    pub(crate) fn write_document_with_text(
+        &mut self,
+        doc: &Document,
+        text: Option<&[u8]>,
+    ) -> Result<()> {
+        let mut buf = vec![];
+        buf.write_u32::<LittleEndian>(doc.id)?;
+        buf.write_u32::<LittleEndian>(doc.length)?;
+        buf.write_u64::<LittleEndian>(doc.fingerprint.size)?;
+        buf.write_u64::<LittleEndian>(doc.fingerprint.mtime)?;
+        buf.write_u64::<LittleEndian>(doc.fingerprint.hash)?;
+        buf.write_u64::<LittleEndian>(doc.path.as_os_str().len() as u64)?;
+        buf.extend(doc.path.as_os_str().as_bytes());
+        if self.flags & FLAG_STORED_TEXT != 0 {
+            let text = text.unwrap_or_default();
+            self.writer.write_all(text)?;
+            buf.write_u64::<LittleEndian>(self.offset)?;
+            buf.write_u64::<LittleEndian>(text.len() as u64)?;
+            buf.write_u32::<LittleEndian>(checksum(text))?;
+            self.offset += text.len() as u64;
+        }
+        self.doc_table.push(DocRecord {
+            id: doc.id,
+            length: doc.length,
+            offset: self.docs_buf.len() as u64,
+            nbytes: buf.len() as u64,
+            crc: checksum(&buf),
+        });
+        self.docs_buf.extend(buf);
+        self.word_count += doc.length as u64;
         Ok(())
     }
 
-    /// Completes the writing process to the index file and finalizes the file structure.
+    /// The term index, with the checksum of the block of entries that starts
+    /// at every sample.
+    fn term_index_buf(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for (i, (position, term)) in self.term_index.iter().enumerate() {
+            let end = self
+                .term_index
+                .get(i + 1)
+                .map_or(self.contents_buf.len(), |(next, _)| *next as usize);
+            let block = &self.contents_buf[*position as usize..end];
+            buf.write_u64::<LittleEndian>(*position).unwrap();
+            buf.write_u32::<LittleEndian>(checksum(block)).unwrap();
+            buf.write_u32::<LittleEndian>(term.len() as u32).unwrap();
+            buf.extend(term.bytes());
+        }
+        buf
+    }
+
+    /// Completes the writing process to the index file and finalizes the file
+    /// structure.
     ///
-    /// This method first writes the accumulated contents entries from the buffer to the file.
-    /// It then updates the file header with the size of the document section and the starting
-    /// position of the contents section, which are crucial for readers to correctly interpret the file data.
-    /// The method ensures all data is flushed to disk and the file is left in a consistent state.
-    pub fn finish(mut self) -> io::Result<()> {
-        let contents_start = self.offset;
+    /// This method writes the data of the documents, the table of entries,
+    /// the table of documents and the term index, in that order. It then
+    /// goes back to fill in the header with the offset and checksum of each
+    /// section, which readers need to find them and to detect damage. All
+    /// data is flushed, leaving the file in a consistent state.
+    pub fn finish(mut self) -> Result<()> {
+        let docs_data_start = self.offset;
+        self.writer.write_all(&self.docs_buf)?;
+        for record in &mut self.doc_table {
+            record.offset += docs_data_start;
+        }
+
+        let contents_start = docs_data_start + self.docs_buf.len() as u64;
         self.writer.write_all(&self.contents_buf)?;
-        println!(
-            "{} bytes main, {} bytes total",
-            contents_start,
-            contents_start + self.contents_buf.len() as u64
+
+        let docs_start = contents_start + self.contents_buf.len() as u64;
+        self.doc_table.sort_by_key(|record| record.id);
+        let mut doc_table_buf =
+            Vec::with_capacity(self.doc_table.len() * DOC_TABLE_RECORD_SIZE as usize);
+        for record in &self.doc_table {
+            record.write_to(&mut doc_table_buf)?;
+        }
+        self.writer.write_all(&doc_table_buf)?;
+
+        let term_index_start = docs_start + doc_table_buf.len() as u64;
+        let term_index_buf = self.term_index_buf();
+        self.writer.write_all(&term_index_buf)?;
+        debug!(
+            main_bytes = contents_start,
+            total_bytes = term_index_start + term_index_buf.len() as u64,
+            "finished index file"
         );
+        let header = IndexHeader {
+            entries_offset: contents_start,
+            docs_offset: docs_start,
+            term_index_offset: term_index_start,
+            entries_crc: checksum(&self.contents_buf),
+            docs_crc: checksum(&doc_table_buf),
+            term_index_crc: checksum(&term_index_buf),
+            ..self.header()
+        };
         self.writer.seek(SeekFrom::Start(0))?;
-        self.writer.write_u64::<LittleEndian>(contents_start)?;
+        header.write_to(&mut self.writer)?;
+        self.writer.flush()?;
         Ok(())
     }
 }
@@ -115,35 +387,35 @@ impl IndexFileWriter {
 /// Writes an in-memory index to a temporary file using a structured binary format.
 ///
 /// This function serializes the contents of an `InMemoryIndex` and writes them into a temporary file.
-/// It organizes the data into two main sections: a document section and an index section. Each section
-/// is preceded by its own size metadata. The file is structured to allow efficient data retrieval based
-/// on the written index and can be used in applications requiring fast lookups.
-pub fn write_index_to_tmp_file(index: InMemoryIndex, tmp_dir: &mut TmpDir) -> io::Result<PathBuf> {
+/// It organizes the data into two main sections: an index section, with the hits of every term
+/// in sorted order, and a document section. Tables at the end of the file describe both sections.
+/// The file is structured to allow efficient data retrieval based on the written index and can be
+/// used in applications requiring fast lookups.
+pub fn write_index_to_tmp_file(index: InMemoryIndex, tmp_dir: &mut TmpDir) -> Result<PathBuf> {
     let (filename, f) = tmp_dir.create()?;
-    let mut writer = IndexFileWriter::new(f)?;
+    let mut writer = IndexFileWriter::new(f, &index.analyzer)?;
+    if index.docs.values().any(|doc| doc.stored.is_some()) {
+        writer.store_text();
+    }
 
     let mut index_as_vec: Vec<_> = index.terms.into_iter().collect();
     index_as_vec.sort_by(|(a, _), (b, _)| a.cmp(b));
 
-    for (term, hits) in index_as_vec {
-        let df = hits.len() as u32;
+    for (term, postings) in index_as_vec {
+        let df = postings.len() as u32;
         let start = writer.offset;
-        for buffer in hits {
-            writer.write_main(&buffer)?;
-        }
+        writer.write_postings(&postings)?;
         let stop = writer.offset;
         writer.write_contents_entry(term, df, start, stop - start);
     }
 
-    // if term == "" && df == 0 { type = document }
-    for (_, doc) in index.docs {
-        let start = writer.offset;
+    let mut docs: Vec<_> = index.docs.into_values().collect();
+    docs.sort_by_key(|doc| doc.id);
+    for doc in docs {
         writer.write_document(&doc)?;
-        let stop = writer.offset;
-        writer.write_contents_entry("".to_string(), 0, start, stop - start)
     }
 
     writer.finish()?;
-    println!("wrote file {:?}", filename);
+    debug!(path = %filename.display(), "wrote temporary index file");
     Ok(filename)
 }
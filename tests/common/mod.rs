@@ -0,0 +1,41 @@
+//! Helpers shared by the integration tests.
+
+// Every test builds this module, and none uses all of it.
+#![allow(dead_code)]
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A fresh, empty directory for the test called `name`.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!(
+        "inverted-index-concurrency-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The `texts` directory of the repository.
+pub fn texts_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("texts")
+}
+
+/// Run `create` in `dir` with `args`, and return the path of the index it
+/// writes.
+pub fn create_index(dir: &Path, args: &[&str]) -> PathBuf {
+    fs::create_dir_all(dir).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_create"))
+        .current_dir(dir)
+        .arg("--quiet")
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success(), "create {:?} failed", args);
+    dir.join("index.bat")
+}
@@ -0,0 +1,69 @@
+//! A damaged index file must be reported as an error, never read as if it
+//! were intact, and never make a reader panic or allocate whatever a
+//! damaged length says.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use common::{create_index, scratch_dir, texts_dir};
+use inverted_index_concurrency::{
+    error::IndexError, index::InMemoryIndex, read::IndexFileReader, verify::verify_index_file,
+};
+
+/// Offset of the table of entries, from the header: it follows the magic
+/// bytes, the format version and the flags.
+fn entries_offset(data: &[u8]) -> usize {
+    u64::from_le_bytes(data[12..20].try_into().unwrap()) as usize
+}
+
+/// Read every entry and document of `index` with `IndexFileReader`.
+fn read_all(index: &Path) -> Result<(), IndexError> {
+    let mut reader = IndexFileReader::open_and_delete(index, false)?;
+    while reader.iter_next_entry()?.is_some() {}
+    reader.read_documents()?;
+    Ok(())
+}
+
+#[test]
+fn damaged_table_of_entries_is_an_error() {
+    let dir = scratch_dir("corrupt");
+    let index = create_index(&dir, &[texts_dir().to_str().unwrap()]);
+    let intact = fs::read(&index).unwrap();
+    let first_entry = entries_offset(&intact);
+
+    // The first entry is the offset (u64) and length (u64) of the hits of
+    // its term, its df, checksum and last document (u32 each), and the
+    // length of its term (u32) followed by the term.
+    for (what, at, mask) in [
+        ("length of hits", first_entry + 15, 0x80),
+        ("length of term", first_entry + 31, 0x80),
+        ("term", first_entry + 32, 0x01),
+    ] {
+        let mut data = intact.clone();
+        data[at] ^= mask;
+        let damaged = dir.join("damaged.bat");
+        fs::write(&damaged, &data).unwrap();
+
+        let err = read_all(&damaged).expect_err(what);
+        assert!(
+            matches!(err, IndexError::CorruptIndex { .. }),
+            "{}: {}",
+            what,
+            err
+        );
+        let err = InMemoryIndex::from_index_file(&damaged).expect_err(what);
+        assert!(
+            matches!(err, IndexError::CorruptIndex { .. }),
+            "{}: {}",
+            what,
+            err
+        );
+        let damage = verify_index_file(&damaged).unwrap();
+        assert!(!damage.is_empty(), "{}", what);
+    }
+
+    assert!(read_all(&index).is_ok());
+    assert!(verify_index_file(&index).unwrap().is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
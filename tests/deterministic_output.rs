@@ -0,0 +1,80 @@
+//! `create` must write a byte-for-byte identical `index.bat` for the same
+//! input, whether it runs single-threaded or as a pipeline, with any number
+//! of threads, and however often it flushes and merges temporary files.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use common::{create_index, scratch_dir, texts_dir};
+
+/// Write a corpus large enough that a small `--memory-budget` flushes many
+/// temporary files.
+fn write_corpus(dir: &Path) {
+    let words: Vec<&str> = "rust index merge thread posting term query phrase score file \
+                            memory budget stream pipeline channel straße café ασφαλής"
+        .split_whitespace()
+        .collect();
+    let mut state: u32 = 12345;
+    for i in 0..60 {
+        let mut text = String::new();
+        for j in 0..(40 + i * 7) {
+            // A small linear congruential generator keeps the corpus fixed.
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            text.push_str(words[(state >> 16) as usize % words.len()]);
+            text.push(if j % 12 == 11 { '\n' } else { ' ' });
+        }
+        fs::write(dir.join(format!("doc{:03}.txt", i)), text).unwrap();
+    }
+}
+
+#[test]
+fn single_threaded_and_pipeline_write_identical_files() {
+    let dir = scratch_dir("deterministic");
+    let corpus = dir.join("corpus");
+    fs::create_dir_all(&corpus).unwrap();
+    write_corpus(&corpus);
+    let texts = texts_dir();
+    let inputs = [corpus.to_str().unwrap(), texts.to_str().unwrap()];
+    let small = ["--memory-budget", "5KiB", "--merge-streams", "2"];
+
+    // Stored text is written to its own section, and copied by every merge.
+    for store in [&[][..], &["--store-text"][..]] {
+        let args = |extra: &[&'static str]| -> Vec<&str> {
+            small
+                .iter()
+                .chain(store)
+                .chain(extra)
+                .chain(&inputs)
+                .copied()
+                .collect()
+        };
+        let single = fs::read(create_index(
+            &dir.join("single"),
+            &args(&["--single-threaded"]),
+        ))
+        .unwrap();
+        assert!(!single.is_empty());
+
+        for (name, extra) in [
+            ("pipeline", &[][..]),
+            (
+                "one-thread",
+                &["--index-threads", "1", "--merge-threads", "1"][..],
+            ),
+            (
+                "many-threads",
+                &["--index-threads", "8", "--merge-threads", "4"][..],
+            ),
+        ] {
+            let pipeline = fs::read(create_index(&dir.join(name), &args(extra))).unwrap();
+            assert_eq!(
+                single, pipeline,
+                "{} {:?} wrote a different file",
+                name, store
+            );
+        }
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
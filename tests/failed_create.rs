@@ -0,0 +1,43 @@
+//! When `create` fails, it must leave the previous index in place, not
+//! replace it with one that misses documents, whether it runs
+//! single-threaded or as a pipeline.
+
+mod common;
+
+use std::{fs, process::Command};
+
+use common::{create_index, scratch_dir};
+
+#[test]
+fn unreadable_document_keeps_the_previous_index() {
+    let dir = scratch_dir("failed-create");
+    let docs = dir.join("d");
+    fs::create_dir_all(&docs).unwrap();
+    fs::write(docs.join("a.txt"), "rust is safe").unwrap();
+    fs::write(docs.join("c.txt"), "rust is fast").unwrap();
+    let index = create_index(&dir, &["d"]);
+    let previous = fs::read(&index).unwrap();
+
+    // Not valid UTF-8. Only `a.txt` is read before it.
+    fs::write(docs.join("b.txt"), b"rust \xff\xfe").unwrap();
+    for extra in [&["--single-threaded"][..], &[][..]] {
+        let output = Command::new(env!("CARGO_BIN_EXE_create"))
+            .current_dir(&dir)
+            .arg("--quiet")
+            .args(extra)
+            .arg("d")
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "create {:?} succeeded", extra);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("b.txt"), "{}", stderr);
+        assert_eq!(
+            fs::read(&index).unwrap(),
+            previous,
+            "create {:?} replaced the index",
+            extra
+        );
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,75 @@
+//! `search` prints every hit under a header naming its file. With
+//! `--format html` or `--format markdown`, the header is markup like the
+//! text below it, so a file name must be escaped like the text.
+
+mod common;
+
+use std::{fs, path::Path, process::Command};
+
+use common::{create_index, scratch_dir};
+
+/// Run `search` in `dir` for `term` with `--format format`, and return what
+/// it printed.
+fn search(dir: &Path, term: &str, format: &str) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_search"))
+        .current_dir(dir)
+        .args(["-i", "index.bat", "-t", term, "--format", format])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "search --format {} failed", format);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Whether `out` has a line that starts with `prefix` and, after the
+/// directory the index records for the file, goes on with `rest`.
+fn has_header(out: &str, prefix: &str, rest: &str) -> bool {
+    out.lines()
+        .any(|line| line.starts_with(prefix) && line.contains(rest))
+}
+
+#[test]
+fn headers_are_escaped_like_the_text() {
+    let dir = scratch_dir("formats");
+    let docs = dir.join("docs");
+    fs::create_dir_all(&docs).unwrap();
+    fs::write(docs.join("a<b>&_[x]*.txt"), "rust & <safe>").unwrap();
+    create_index(&dir, &["docs"]);
+
+    let html = search(&dir, "rust", "html");
+    assert!(
+        has_header(&html, "<b>/", "/docs/a&lt;b&gt;&amp;_[x]*.txt</b> (score "),
+        "{}",
+        html
+    );
+    assert!(
+        html.ends_with(": \n<mark>rust</mark> &amp; &lt;safe&gt;\n"),
+        "{}",
+        html
+    );
+
+    let markdown = search(&dir, "rust", "markdown");
+    assert!(
+        has_header(
+            &markdown,
+            "**/",
+            "/docs/a\\<b\\>&\\_\\[x\\]\\*.txt** \\(score "
+        ),
+        "{}",
+        markdown
+    );
+    assert!(
+        markdown.ends_with("\\): \n**rust** & \\<safe\\>\n"),
+        "{}",
+        markdown
+    );
+
+    // A terminal shows the path as Rust would debug-print it.
+    let ansi = search(&dir, "rust", "ansi");
+    assert!(
+        has_header(&ansi, "\"/", "/docs/a<b>&_[x]*.txt\" (score "),
+        "{}",
+        ansi
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
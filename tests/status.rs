@@ -0,0 +1,125 @@
+//! `status` compares an index with the files it should cover. Files must be
+//! matched however their paths are spelled, and only real changes count.
+
+mod common;
+
+use std::{fs, path::Path, process::Command};
+
+use common::{create_index, scratch_dir, texts_dir};
+
+/// Run `status` in `dir` with `args`, and return whether it succeeded along
+/// with what it printed.
+fn status(dir: &Path, args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_status"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+#[test]
+fn same_files_named_differently_are_not_new() {
+    let dir = scratch_dir("status");
+    let texts = dir.join("texts");
+    fs::create_dir_all(&texts).unwrap();
+    for entry in fs::read_dir(texts_dir()).unwrap() {
+        let path = entry.unwrap().path();
+        fs::copy(&path, texts.join(path.file_name().unwrap())).unwrap();
+    }
+    let texts = fs::canonicalize(&texts).unwrap();
+    let work = dir.join("work");
+    create_index(&work, &["../texts"]);
+
+    for texts_arg in ["../texts", "./../texts", texts.to_str().unwrap()] {
+        let (ok, out) = status(&work, &["-i", "index.bat", texts_arg]);
+        assert!(ok, "{}", out);
+        assert!(
+            out.ends_with(": 0 new, 0 modified, 0 deleted, 0 unreadable, 7 unchanged\n"),
+            "{}",
+            out
+        );
+    }
+
+    // Touching a file doesn't change it; editing it does.
+    let touched = fs::read(texts.join("text1.txt")).unwrap();
+    fs::write(texts.join("text1.txt"), touched).unwrap();
+    fs::write(texts.join("text2.txt"), "edited").unwrap();
+    fs::write(texts.join("new.txt"), "new").unwrap();
+    fs::remove_file(texts.join("text3.txt")).unwrap();
+    let (ok, out) = status(&work, &["-i", "index.bat", "./../texts"]);
+    assert!(!ok);
+    assert_eq!(
+        out,
+        format!(
+            "modified: {}\n\
+             deleted:  {}\n\
+             new:      ./../texts/new.txt\n\
+             index.bat: 1 new, 1 modified, 1 deleted, 0 unreadable, 5 unchanged\n",
+            texts.join("text2.txt").display(),
+            texts.join("text3.txt").display()
+        )
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn status_works_from_another_directory() {
+    let dir = scratch_dir("status-elsewhere");
+    let docs = dir.join("index").join("d");
+    fs::create_dir_all(&docs).unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        fs::write(docs.join(name), name).unwrap();
+    }
+    let index = create_index(&dir.join("index"), &["d"]);
+
+    let (ok, out) = status(
+        &dir,
+        &["-i", index.to_str().unwrap(), docs.to_str().unwrap()],
+    );
+    assert!(ok, "{}", out);
+    assert!(
+        out.ends_with(": 0 new, 0 modified, 0 deleted, 0 unreadable, 3 unchanged\n"),
+        "{}",
+        out
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unreadable_files_are_reported_and_the_rest_checked() {
+    let dir = scratch_dir("status-unreadable");
+    for sub in ["d", "e"] {
+        fs::create_dir_all(dir.join(sub)).unwrap();
+        fs::write(dir.join(sub).join("a.txt"), sub).unwrap();
+    }
+    create_index(&dir, &["d", "e"]);
+
+    // `d/a.txt` can't be looked at once `d` is not a directory.
+    let d = fs::canonicalize(dir.join("d")).unwrap();
+    fs::remove_dir_all(&d).unwrap();
+    fs::write(&d, "not a directory").unwrap();
+    fs::write(dir.join("e").join("a.txt"), "edited").unwrap();
+
+    let (ok, out) = status(&dir, &["-i", "index.bat"]);
+    assert!(!ok);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 3, "{}", out);
+    assert!(
+        lines[0].starts_with(&format!("unreadable: {}: ", d.join("a.txt").display())),
+        "{}",
+        out
+    );
+    assert!(lines[1].starts_with("modified: "), "{}", out);
+    assert_eq!(
+        lines[2],
+        "index.bat: 0 new, 1 modified, 0 deleted, 1 unreadable, 0 unchanged"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
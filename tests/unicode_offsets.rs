@@ -0,0 +1,150 @@
+//! Hits record the byte range of every token in the original text, so that
+//! highlights and snippets line up in any language. The Turkish, German,
+//! Greek and emoji documents in `texts` are the cases that break when
+//! offsets are taken from a lowercased copy of the text instead.
+
+mod common;
+
+use std::{fs, path::Path, time::SystemTime};
+
+use common::{create_index, scratch_dir, texts_dir};
+use inverted_index_concurrency::{
+    analysis::Analyzer,
+    highlight::{spans, Highlighter, Markdown, Span},
+    index::InMemoryIndex,
+    query::Query,
+    results::SearchHit,
+    score::Bm25,
+    searcher::IndexSearcher,
+    snippet::snippets,
+};
+
+/// The path and text of every document in `texts`, sorted by name.
+fn texts() -> Vec<(String, String)> {
+    let mut texts: Vec<_> = fs::read_dir(texts_dir())
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let text = fs::read_to_string(&path).unwrap();
+            (path.to_str().unwrap().to_string(), text)
+        })
+        .collect();
+    texts.sort();
+    texts
+}
+
+#[test]
+fn every_position_is_a_range_of_the_original_text() {
+    for analyzer in [Analyzer::standard(), Analyzer::whitespace()] {
+        for (id, (path, text)) in texts().into_iter().enumerate() {
+            let index = InMemoryIndex::from_single_document(
+                &analyzer,
+                id as u32 + 1,
+                path.clone().into(),
+                text.clone(),
+                SystemTime::UNIX_EPOCH,
+                false,
+            );
+            for (term, postings) in &index.terms {
+                for pos in postings.iter().flat_map(|posting| &posting.positions) {
+                    let (start, end) = (pos.start_pos as usize, pos.end_pos as usize);
+                    assert!(start < end && end <= text.len(), "{} in {}", term, path);
+                    assert!(
+                        text.is_char_boundary(start) && text.is_char_boundary(end),
+                        "{} in {} at {}..{}",
+                        term,
+                        path,
+                        start,
+                        end
+                    );
+                    // The range is exactly the token the term came from.
+                    let tokens = analyzer.analyze(&text[start..end]);
+                    assert_eq!(tokens.len(), 1, "{} in {}", term, path);
+                    assert_eq!(&tokens[0].text, term, "in {}", path);
+                }
+            }
+        }
+    }
+}
+
+/// Run `query` against `index`, and return the only hit along with the
+/// text of its document.
+fn search_one(index: &Path, query: &str) -> (String, SearchHit) {
+    let searcher = IndexSearcher::open(index).unwrap();
+    let query = Query::parse(query, &Analyzer::standard()).unwrap();
+    let mut results = searcher.search(&query, &Bm25::default(), 10).unwrap().hits;
+    assert_eq!(results.len(), 1, "{:?}", results);
+    let hit = results.remove(0);
+    (fs::read_to_string(&hit.path).unwrap(), hit)
+}
+
+/// The text of every span of `text`.
+fn matched<'a>(text: &'a str, spans: &[Span]) -> Vec<&'a str> {
+    spans
+        .iter()
+        .map(|span| &text[span.start..span.end])
+        .collect()
+}
+
+#[test]
+fn queries_find_and_highlight_non_ascii_words() {
+    let dir = scratch_dir("unicode");
+    let index = create_index(&dir, &[texts_dir().to_str().unwrap()]);
+
+    for (query, expected) in [
+        // 'İ' lowercases to a plain 'i', and is two bytes long where 'i' is
+        // one.
+        ("istanbul", vec!["İSTANBUL"]),
+        ("işlemci", vec!["İşlemci"]),
+        ("çiçek", vec!["ÇİÇEK"]),
+        // 'ß' stays 'ß'; 'Ä', 'Ö' and 'Ü' lowercase within two bytes.
+        ("straße", vec!["Straße"]),
+        ("größe OR ärger", vec!["Größe", "ÄRGER"]),
+        // A final 'Σ' lowercases to 'ς', and the query is lowercased the
+        // same way.
+        ("ασφαλης", vec!["ΑΣΦΑΛΗΣ"]),
+        ("ΠΡΟΓΡΑΜΜΑΤΙΣΜΟΣ", vec!["ΠΡΟΓΡΑΜΜΑΤΙΣΜΟΣ"]),
+        // Words right next to emoji, and a word with an accent.
+        ("safe AND fun", vec!["safe", "fun"]),
+        ("rust AND flags", vec!["Rust", "flags"]),
+        ("café", vec!["café"]),
+        ("flags", vec!["flags"]),
+    ] {
+        let (text, hit) = search_one(&index, query);
+        let spans = spans(&text, 0, &hit.matches);
+        assert_eq!(spans.len(), hit.matches.len(), "{}", query);
+        assert_eq!(matched(&text, &spans), expected, "{}", query);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn snippets_cut_non_ascii_text_on_word_boundaries() {
+    let dir = scratch_dir("unicode-snippets");
+    let index = create_index(&dir, &[texts_dir().to_str().unwrap()]);
+    let analyzer = Analyzer::standard();
+    let highlighter = Highlighter::new(Markdown);
+
+    for (query, expected) in [
+        ("işlemci", "eğlenceli: **İşlemci**, ışık"),
+        ("istanbul", "**İSTANBUL**'da"),
+        ("straße", "der **Straße**: Rust"),
+        ("ασφαλης", "είναι **ΑΣΦΑΛΗΣ** και"),
+        // "safe" is in another document too; the earliest of equally good
+        // windows is kept.
+        ("safe AND café", "is 💯 **safe**, 👩‍💻 fast"),
+        // The last word of the document: the snippet runs to its end.
+        ("flags", "and 🇹🇷 **flags**.\n"),
+    ] {
+        let (text, hit) = search_one(&index, query);
+        let snippets = snippets(&text, &analyzer, &hit.matches, 1, 1);
+        assert_eq!(snippets.len(), 1, "{}", query);
+        let snippet = &snippets[0];
+        assert!(text.is_char_boundary(snippet.start) && text.is_char_boundary(snippet.end));
+        let shown = highlighter.highlight(snippet.text(&text), snippet.start, &snippet.matches);
+        assert_eq!(shown, expected, "{}", query);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}